@@ -1,67 +1,41 @@
+use brainfuck::{Engine, Options, Tape};
 use criterion::Criterion;
 use criterion::*;
-
-use brainfuck;
-
-macro_rules! bench_bf {
-    ($c:ident, $bf:expr, $opt:expr) => {
-        let bytes = include_bytes!(concat!("brainfuck/", $bf, ".bf"));
-        $c.bench_function(&format!("{}{}", $bf, $opt), move |b| {
-            b.iter(|| {
-                let mut out = Vec::new();
-                brainfuck::run(bytes, &[] as &[u8], &mut out, Some($opt), false).unwrap();
-            })
-        });
-    };
-}
-
-fn bench_trivial_loop_1(c: &mut Criterion) {
-    bench_bf!(c, "trivial-loop", 1);
-}
-
-fn bench_trivial_loop_2(c: &mut Criterion) {
-    bench_bf!(c, "trivial-loop", 2);
+use std::path::Path;
+
+// Every program in benches/brainfuck on every engine, with compiling and running benchmarked
+// separately as "<program>/<engine>/compile" and "<program>/<engine>/run".
+fn bench_engines(c: &mut Criterion) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/brainfuck");
+    for path in brainfuck::testcase::programs(dir).unwrap() {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let s = std::fs::read(&path).unwrap();
+        let input = brainfuck::testcase::feed_in(&path).unwrap();
+        for engine in Engine::all() {
+            let program = engine.compile(&s, &Options::default()).unwrap();
+            let input = input.clone();
+            let s = s.clone();
+            c.bench_function(&format!("{}/{}/compile", name, engine), move |b| {
+                b.iter(|| engine.compile(&s, &Options::default()).unwrap())
+            });
+            c.bench_function(&format!("{}/{}/run", name, engine), move |b| {
+                b.iter_with_setup(
+                    || Tape::new(engine.memory_size()),
+                    |mut tape| {
+                        program
+                            .run_on_tape(&input[..], std::io::sink(), std::io::sink(), &mut tape)
+                            .unwrap()
+                    },
+                )
+            });
+        }
+    }
+}
+
+criterion_group! {
+    name = benches;
+    // mandelbrot.bf runs for tens of seconds on the slower interpreters.
+    config = Criterion::default().sample_size(10);
+    targets = bench_engines
 }
-
-fn bench_trivial_loop_3(c: &mut Criterion) {
-    bench_bf!(c, "trivial-loop", 3);
-}
-
-fn bench_nested_loop_1(c: &mut Criterion) {
-    bench_bf!(c, "nested-loop", 1);
-}
-
-fn bench_nested_loop_2(c: &mut Criterion) {
-    bench_bf!(c, "nested-loop", 2);
-}
-
-fn bench_nested_loop_3(c: &mut Criterion) {
-    bench_bf!(c, "nested-loop", 3);
-}
-
-fn bench_sierpinski_1(c: &mut Criterion) {
-    bench_bf!(c, "sierpinski", 1);
-}
-
-fn bench_sierpinski_2(c: &mut Criterion) {
-    bench_bf!(c, "sierpinski", 2);
-}
-
-fn bench_sierpinski_3(c: &mut Criterion) {
-    bench_bf!(c, "sierpinski", 3);
-}
-
-criterion_group!(
-    benches,
-    bench_trivial_loop_1,
-    bench_trivial_loop_2,
-    bench_trivial_loop_3,
-    bench_nested_loop_1,
-    bench_nested_loop_2,
-    bench_nested_loop_3,
-    bench_sierpinski_1,
-    bench_sierpinski_2,
-    bench_sierpinski_3,
-);
-// criterion_group!(benches, bench_trivial_loop);
 criterion_main!(benches);
@@ -0,0 +1,559 @@
+// Abstract interpretation of the `Op`s of a program, which finds, before each op, the range of
+// cells the data pointer may be at and the cells known to hold a constant, whatever the input.
+//
+// The analysis is a dataflow over the ops, which joins the states reaching an op until they no
+// longer change. A range still growing after a few joins is widened to have no bound on that
+// side, so that it ends. Programs with procedures or forks are not analyzed.
+use crate::brainfuck::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// Number of joins at an op before its pointer range is widened.
+const WIDEN_AFTER: usize = 3;
+// Widest range of cells which are forgotten one by one when one of them is written.
+const MAX_FORGOTTEN: usize = 64;
+// Most cells a state tracks, as there is a state for every op. Past it, every cell is forgotten.
+const MAX_CELLS: usize = 256;
+// Most times the ops are analyzed again to fold more loops.
+const MAX_FOLD_ROUNDS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    // The lowest and highest cell the data pointer may be at. `None` has no bound.
+    lo: usize,
+    hi: Option<usize>,
+    // Cells which hold a constant, or are not known (`None`), and what every other cell holds.
+    cells: BTreeMap<usize, Option<u8>>,
+    rest: Option<u8>,
+}
+
+fn join_value(a: Option<u8>, b: Option<u8>) -> Option<u8> {
+    if a == b {
+        a
+    } else {
+        None
+    }
+}
+
+impl State {
+    // A fresh tape.
+    fn initial() -> State {
+        State {
+            lo: 0,
+            hi: Some(0),
+            cells: BTreeMap::new(),
+            rest: Some(0),
+        }
+    }
+
+    // The data pointer, if it is known.
+    fn data_ptr(&self) -> Option<usize> {
+        if self.hi == Some(self.lo) {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    fn cell(&self, i: usize) -> Option<u8> {
+        self.cells.get(&i).cloned().unwrap_or(self.rest)
+    }
+
+    // The value of the current cell, if it is known.
+    fn current(&self) -> Option<u8> {
+        self.data_ptr().and_then(|i| self.cell(i))
+    }
+
+    fn set(&mut self, i: usize, value: Option<u8>) {
+        if value == self.rest {
+            self.cells.remove(&i);
+        } else {
+            self.cells.insert(i, value);
+            if self.cells.len() > MAX_CELLS {
+                self.cells.clear();
+                self.rest = None;
+            }
+        }
+    }
+
+    // Forgets the cells from `lo` to `hi`, which may have been written.
+    fn forget(&mut self, lo: usize, hi: Option<usize>) {
+        match hi {
+            Some(hi) if hi - lo < MAX_FORGOTTEN => {
+                for i in lo..=hi {
+                    self.set(i, None);
+                }
+            }
+            _ => {
+                // The cells below the range still hold what the others did.
+                if let Some(rest) = self.rest {
+                    if lo <= MAX_FORGOTTEN {
+                        for i in 0..lo {
+                            self.cells.entry(i).or_insert(Some(rest));
+                        }
+                    }
+                }
+                self.cells.retain(|i, value| {
+                    (*i < lo || hi.is_some_and(|hi| *i > hi)) && value.is_some()
+                });
+                self.rest = None;
+            }
+        }
+    }
+
+    // Sets the current cell to `value`, or forgets every cell the pointer may be at.
+    fn set_current(&mut self, value: Option<u8>) {
+        match self.data_ptr() {
+            Some(i) => self.set(i, value),
+            None => self.forget(self.lo, self.hi),
+        }
+    }
+
+    fn join(&self, other: &State) -> State {
+        let mut joined = State {
+            lo: std::cmp::min(self.lo, other.lo),
+            hi: self.hi.and_then(|a| other.hi.map(|b| std::cmp::max(a, b))),
+            cells: BTreeMap::new(),
+            rest: join_value(self.rest, other.rest),
+        };
+        for i in self.cells.keys().chain(other.cells.keys()) {
+            let value = join_value(self.cell(*i), other.cell(*i));
+            joined.set(*i, value);
+        }
+        joined
+    }
+
+    // `joined`, which is `self` joined with another state, with the bounds of the pointer which
+    // moved dropped.
+    fn widen(&self, mut joined: State) -> State {
+        if joined.lo < self.lo {
+            joined.lo = 0;
+        }
+        if joined.hi != self.hi {
+            joined.hi = None;
+        }
+        joined
+    }
+}
+
+// The state before each op of a program.
+pub(crate) struct Analysis {
+    states: Vec<Option<State>>,
+}
+
+// Whether `ops` can be analyzed.
+fn is_supported(ops: &[Op]) -> bool {
+    !ops.iter().any(|op| {
+        matches!(
+            op,
+            Op::DefineProcedure(_) | Op::EndProcedure | Op::CallProcedure | Op::Fork
+        )
+    })
+}
+
+impl Analysis {
+    pub(crate) fn new(ops: &[Op]) -> Option<Analysis> {
+        if !is_supported(ops) {
+            return None;
+        }
+        // The state before each op, and after the last. `None` is never reached.
+        let mut states: Vec<Option<State>> = vec![None; ops.len() + 1];
+        let mut joins = vec![0; ops.len() + 1];
+        states[0] = Some(State::initial());
+        let mut pending: BTreeSet<usize> = BTreeSet::new();
+        pending.insert(0);
+        while let Some(pc) = pending.iter().next().cloned() {
+            pending.remove(&pc);
+            if pc == ops.len() {
+                continue;
+            }
+            let state = states[pc].clone().unwrap();
+            for (next, state) in transfer(&ops[pc], pc, state) {
+                let merged = match &states[next] {
+                    None => state,
+                    Some(old) => {
+                        let joined = old.join(&state);
+                        joins[next] += 1;
+                        if joins[next] > WIDEN_AFTER {
+                            old.widen(joined)
+                        } else {
+                            joined
+                        }
+                    }
+                };
+                if states[next].as_ref() != Some(&merged) {
+                    states[next] = Some(merged);
+                    pending.insert(next);
+                }
+            }
+        }
+        Some(Analysis { states })
+    }
+
+    // The highest cell the pointer may reach, if it is bounded.
+    pub(crate) fn max_cell(&self, ops: &[Op]) -> Option<usize> {
+        let mut max = 0;
+        for (pc, state) in self.states.iter().enumerate() {
+            if let Some(state) = state {
+                let mut hi = state.hi?;
+                if let Some(Op::LoopMoveData(n)) = ops.get(pc) {
+                    if *n > 0 && state.current() != Some(0) {
+                        hi += *n as usize;
+                    }
+                }
+                max = std::cmp::max(max, hi);
+            }
+        }
+        Some(max)
+    }
+
+    // The highest cell the pointer may reach, if it has a bound and the pointer never moves left
+    // of cell 0, so that a tape with more cells needs no bounds checks.
+    pub(crate) fn tape_bound(&self, ops: &[Op]) -> Option<usize> {
+        if (0..ops.len()).any(|pc| self.may_leave_tape(ops, pc, usize::MAX)) {
+            return None;
+        }
+        self.max_cell(ops)
+    }
+
+    // Whether the op at `pc` may move the pointer off a tape of `memory_size` cells.
+    pub(crate) fn may_leave_tape(&self, ops: &[Op], pc: usize, memory_size: usize) -> bool {
+        let state = match &self.states[pc] {
+            Some(state) => state,
+            None => return false,
+        };
+        let below = |n: usize| state.lo < n;
+        let above = |n: usize| state.hi.is_none_or(|hi| hi + n >= memory_size);
+        match ops[pc] {
+            Op::IncPtr(n) => above(n),
+            Op::DecPtr(n) => below(n),
+            Op::LoopMovePtr(_) | Op::LoopMoveData(_) if state.current() == Some(0) => false,
+            // A scan may go any number of cells.
+            Op::LoopMovePtr(_) => true,
+            Op::LoopMoveData(n) if n < 0 => below(-n as usize),
+            Op::LoopMoveData(n) => above(n as usize),
+            _ => false,
+        }
+    }
+
+    // The value of the current cell before the op at `pc`, if it is always the same.
+    pub(crate) fn current_cell(&self, pc: usize) -> Option<u8> {
+        self.states[pc].as_ref().and_then(|state| state.current())
+    }
+
+    // `ops` with the loops whose counter is known folded: a loop whose counter is zero is dropped,
+    // and a loop which only adds to cells around its counter adds what all its iterations would.
+    // The folded loops move the pointer as one iteration does, so they may leave the tape at
+    // another point than the loops would.
+    fn fold_loops(&self, ops: &[Op]) -> Vec<Op> {
+        let mut folded = Vec::new();
+        let mut open_loops = Vec::new();
+        let mut pc = 0;
+        while pc < ops.len() {
+            match ops[pc] {
+                Op::JumpIfDataIsZero(end) => match self.current_cell(pc) {
+                    Some(0) => {
+                        pc = end + 1;
+                        continue;
+                    }
+                    Some(counter) => match fold_loop(&ops[pc + 1..end], counter) {
+                        Some(body) => {
+                            folded.extend(body);
+                            pc = end + 1;
+                            continue;
+                        }
+                        None => {
+                            open_loops.push(folded.len());
+                            folded.push(Op::JumpIfDataIsZero(0));
+                        }
+                    },
+                    None => {
+                        open_loops.push(folded.len());
+                        folded.push(Op::JumpIfDataIsZero(0));
+                    }
+                },
+                Op::JumpIfDataIsNotZero(_) => {
+                    let start = open_loops.pop().unwrap();
+                    folded[start] = Op::JumpIfDataIsZero(folded.len());
+                    folded.push(Op::JumpIfDataIsNotZero(start));
+                }
+                op => folded.push(op),
+            }
+            pc += 1;
+        }
+        folded
+    }
+}
+
+// Folds the loops of `ops` whose counter is known (see `Analysis::fold_loops`). The cells a folded
+// loop writes are known, so the ops are analyzed again for the loops after it.
+pub(crate) fn fold_constant_loops(mut ops: Vec<Op>) -> Vec<Op> {
+    for _ in 0..MAX_FOLD_ROUNDS {
+        let folded = match Analysis::new(&ops) {
+            Some(analysis) => analysis.fold_loops(&ops),
+            None => break,
+        };
+        if folded.len() == ops.len() {
+            break;
+        }
+        ops = folded;
+    }
+    ops
+}
+
+// The ops which do what the loop with `body` does when its counter is `counter`, if the body only
+// moves the pointer and adds to cells, and ends at the counter.
+fn fold_loop(body: &[Op], counter: u8) -> Option<Vec<Op>> {
+    let mut offset = 0;
+    // What an iteration adds to the counter.
+    let mut step: u8 = 0;
+    for op in body {
+        match *op {
+            Op::IncPtr(n) => offset += n as isize,
+            Op::DecPtr(n) => offset -= n as isize,
+            Op::IncData(n) if offset == 0 => step = step.wrapping_add(n as u8),
+            Op::DecData(n) if offset == 0 => step = step.wrapping_sub(n as u8),
+            Op::IncData(_) | Op::DecData(_) => {}
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    // A loop whose counter never reaches zero is left to run forever.
+    let iterations = (1..256).find(|i| counter.wrapping_add(step.wrapping_mul(*i as u8)) == 0)?;
+    let mut offset = 0;
+    let mut folded: Vec<Op> = body
+        .iter()
+        .filter_map(|op| match *op {
+            Op::IncPtr(n) => {
+                offset += n as isize;
+                Some(*op)
+            }
+            Op::DecPtr(n) => {
+                offset -= n as isize;
+                Some(*op)
+            }
+            _ if offset == 0 => None,
+            Op::IncData(n) => Some(Op::IncData(n * iterations)),
+            Op::DecData(n) => Some(Op::DecData(n * iterations)),
+            _ => unreachable!(),
+        })
+        .collect();
+    folded.push(Op::LoopSetToZero);
+    Some(folded)
+}
+
+// The ops which may run after `op`, which is at `pc`, with the state before each.
+fn transfer(op: &Op, pc: usize, mut state: State) -> Vec<(usize, State)> {
+    let current = state.current();
+    match *op {
+        Op::IncPtr(n) => {
+            state.lo += n;
+            state.hi = state.hi.map(|hi| hi + n);
+        }
+        Op::DecPtr(n) => {
+            // A program which moves off the tape stops.
+            if state.hi.is_some_and(|hi| hi < n) {
+                return vec![];
+            }
+            state.lo = state.lo.saturating_sub(n);
+            state.hi = state.hi.map(|hi| hi - n);
+        }
+        Op::IncData(n) => state.set_current(current.map(|v| v.wrapping_add(n as u8))),
+        Op::DecData(n) => state.set_current(current.map(|v| v.wrapping_sub(n as u8))),
+        Op::ReadStdin(_) | Op::Load | Op::Xor | Op::And | Op::Or => state.set_current(None),
+        Op::WriteStdout(_) | Op::DebugDump(_) | Op::Store => {}
+        Op::ShiftLeft(_) | Op::ShiftRight(_) | Op::Not => {
+            state.set_current(current.map(|v| apply_bitwise(op, v, 0)))
+        }
+        Op::LoopSetToZero => state.set_current(Some(0)),
+        Op::LoopMovePtr(_) | Op::LoopMoveData(_) if current == Some(0) => {}
+        Op::LoopMovePtr(n) => {
+            if n < 0 {
+                state.lo = 0;
+            } else {
+                state.hi = None;
+            }
+        }
+        Op::LoopMoveData(n) => match state.data_ptr() {
+            Some(from) if from as isize + n >= 0 => {
+                let to = (from as isize + n) as usize;
+                let sum = current.and_then(|a| state.cell(to).map(|b| b.wrapping_add(a)));
+                state.set(to, sum);
+                state.set(from, Some(0));
+            }
+            // The pointer would move left of cell 0, so only a loop which never runs goes on.
+            Some(_) if current.is_some() => return vec![],
+            Some(from) => state.set(from, Some(0)),
+            None => {
+                let lo = (state.lo as isize + std::cmp::min(n, 0)).max(0) as usize;
+                let hi = state.hi.map(|hi| hi + std::cmp::max(n, 0) as usize);
+                state.forget(lo, hi);
+            }
+        },
+        Op::JumpIfDataIsZero(end) => return branch(pc + 1, end + 1, state),
+        Op::JumpIfDataIsNotZero(start) => return branch(start + 1, pc + 1, state),
+        Op::End => return vec![],
+        Op::DefineProcedure(_) | Op::EndProcedure | Op::CallProcedure | Op::Fork => {
+            unreachable!()
+        }
+    }
+    vec![(pc + 1, state)]
+}
+
+// Goes to `nonzero` or `zero` by the current cell.
+fn branch(nonzero: usize, zero: usize, state: State) -> Vec<(usize, State)> {
+    let mut next = Vec::new();
+    match state.current() {
+        Some(0) => {}
+        _ => next.push((nonzero, state.clone())),
+    }
+    if state.current().is_none_or(|v| v == 0) {
+        let mut state = state;
+        if state.data_ptr().is_some() {
+            state.set_current(Some(0));
+        }
+        next.push((zero, state));
+    }
+    next
+}
+
+// What the analysis finds about a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    // The number of cells the program stays within, if it is bounded.
+    pub cells: Option<usize>,
+    // Ops which move the pointer, and those of them which never move it off the tape.
+    pub moves: usize,
+    pub moves_in_bounds: usize,
+    // Loops, and those of them whose counter is known each time they are reached.
+    pub loops: usize,
+    pub constant_loops: usize,
+}
+
+// Analyzes `s`, as it is run on a tape of `memory_size` cells.
+pub fn analyze(s: &[u8], options: &Options, memory_size: usize) -> Result<Report> {
+    check_program_brackets(s, options)?;
+    let ops = translate_program(&filter_instructions(s, options), true);
+    let analysis = Analysis::new(&ops).ok_or_else(|| {
+        failure::format_err!("programs with procedures or forks can not be analyzed")
+    })?;
+    let mut report = Report {
+        cells: analysis.max_cell(&ops).map(|max| max + 1),
+        moves: 0,
+        moves_in_bounds: 0,
+        loops: 0,
+        constant_loops: 0,
+    };
+    for (pc, op) in ops.iter().enumerate() {
+        match op {
+            Op::IncPtr(_) | Op::DecPtr(_) | Op::LoopMovePtr(_) | Op::LoopMoveData(_) => {
+                report.moves += 1;
+                if !analysis.may_leave_tape(&ops, pc, memory_size) {
+                    report.moves_in_bounds += 1;
+                }
+            }
+            Op::JumpIfDataIsZero(_) => {
+                report.loops += 1;
+                if analysis.current_cell(pc).is_some() {
+                    report.constant_loops += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn analyze(s: &[u8]) -> Report {
+        super::analyze(s, &Options::default(), 30000).unwrap()
+    }
+
+    #[test]
+    fn analyze_test() {
+        // The counter of the first loop is known, and the last move goes left of cell 0.
+        let s = b"++++[>++<-]>[->+>+<<]>>[-]<<<<";
+        assert_eq!(
+            analyze(s),
+            Report {
+                cells: Some(4),
+                moves: 8,
+                moves_in_bounds: 7,
+                loops: 2,
+                constant_loops: 1,
+            }
+        );
+        // A scan has no bound.
+        assert_eq!(analyze(b"+[>+]").cells, None);
+        assert_eq!(analyze(b",[>,]").cells, None);
+        // The scan never runs, as its cell is zero.
+        assert_eq!(analyze(b">>[>]<,[->>+<<]").cells, Some(4));
+        // With input 0, the loop which would move data left of cell 0 never runs.
+        assert_eq!(analyze(b",[-<+>]>>>>>>>>+[-]").cells, Some(9));
+        assert_eq!(
+            analyze(b",[-<+>]<"),
+            Report {
+                cells: Some(1),
+                moves: 2,
+                moves_in_bounds: 0,
+                loops: 0,
+                constant_loops: 0,
+            }
+        );
+        // Too many cells to track.
+        let s = [&b">"[..], &b"+>".repeat(20000), b"<[<]+[>>[>]<[<]<-]"].concat();
+        assert_eq!(analyze(&s).cells, None);
+
+        let ops = translate_program(b"+[->+<]>>.<<<", true);
+        let analysis = Analysis::new(&ops).unwrap();
+        assert_eq!(analysis.current_cell(1), Some(1));
+        assert_eq!(analysis.current_cell(3), Some(0));
+        assert!(!analysis.may_leave_tape(&ops, 2, 3));
+        assert!(analysis.may_leave_tape(&ops, 2, 2));
+        assert!(analysis.may_leave_tape(&ops, 4, 30000));
+    }
+
+    #[test]
+    fn fold_constant_loops_test() {
+        let loops = |s: &[u8]| {
+            let ops = translate_program(s, true);
+            let folded = fold_constant_loops(ops.clone());
+            let count = |ops: &[Op]| {
+                ops.iter()
+                    .filter(|op| matches!(op, Op::JumpIfDataIsZero(_)))
+                    .count()
+            };
+            (count(&ops), count(&folded))
+        };
+        // The last loop never runs.
+        let s = b"++++[>++>+++<<-]>>[<+>-]<[>>+<<--]>>>[+.]<<<.>.>.";
+        assert_eq!(loops(s), (4, 0));
+        // The counter never reaches zero.
+        assert_eq!(loops(b"+[>+<++]"), (1, 1));
+
+        let run = |engine: Engine| {
+            let mut out = Vec::new();
+            let mut tape = Tape::new(10);
+            engine
+                .run_on_tape(
+                    s,
+                    &Options::default(),
+                    &[] as &[u8],
+                    &mut out,
+                    std::io::sink(),
+                    &mut tape,
+                )
+                .unwrap();
+            (out, tape.memory)
+        };
+        assert_eq!(run(Engine::Interpreter3), run(Engine::Interpreter1));
+        assert_eq!(run(Engine::Interpreter3).0, b"\0\0\n");
+    }
+}
@@ -0,0 +1,174 @@
+// Benchmarks comparing the engines, with the time to compile a program kept apart from the time to
+// run it.
+use crate::brainfuck::*;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub engine: Engine,
+    pub compile: Duration,
+    pub run: Duration,
+}
+
+// The fastest of `iterations` calls of `f`, each given a fresh value from `setup`, which is not
+// timed.
+fn fastest<S>(
+    iterations: usize,
+    mut setup: impl FnMut() -> S,
+    mut f: impl FnMut(S) -> Result<()>,
+) -> Result<Duration> {
+    let mut fastest = None;
+    for _ in 0..iterations {
+        let state = setup();
+        let start = Instant::now();
+        f(state)?;
+        let elapsed = start.elapsed();
+        fastest = Some(fastest.map_or(elapsed, |d: Duration| d.min(elapsed)));
+    }
+    fastest.ok_or_else(|| failure::err_msg("iterations must be at least 1"))
+}
+
+// Compiles and runs `s` on `engine` `iterations` times, and keeps the fastest of each, which is
+// the least disturbed by the rest of the system. The output is discarded.
+pub fn measure(
+    s: &[u8],
+    input: &[u8],
+    engine: Engine,
+    options: &Options,
+    iterations: usize,
+) -> Result<Measurement> {
+    let compile = fastest(
+        iterations,
+        || (),
+        |()| engine.compile(s, options).map(|_| ()),
+    )?;
+    let program = engine.compile(s, options)?;
+    let run = fastest(
+        iterations,
+        || Tape::new(engine.memory_size()),
+        |mut tape| program.run_on_tape(input, std::io::sink(), std::io::sink(), &mut tape),
+    )?;
+    Ok(Measurement {
+        engine,
+        compile,
+        run,
+    })
+}
+
+// Runs `measure` for every `*.bf` in `dir` on each of `engines`. A program is fed with the
+// `feed-in` of its `.test` file if there is one.
+pub fn bench_dir(
+    dir: impl AsRef<Path>,
+    engines: &[Engine],
+    options: &Options,
+    iterations: usize,
+) -> Result<Vec<(PathBuf, Vec<Measurement>)>> {
+    crate::testcase::programs(dir)?
+        .into_iter()
+        .map(|path| {
+            let program = std::fs::read(&path)?;
+            let input = crate::testcase::feed_in(&path)?;
+            let measurements = engines
+                .iter()
+                .map(|engine| measure(&program, &input, *engine, options, iterations))
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| failure::format_err!("{}: {}", path.display(), e))?;
+            Ok((path, measurements))
+        })
+        .collect()
+}
+
+// Formats `d` in the largest unit it has at least one of, with 3 decimals.
+pub fn format_duration(d: Duration) -> String {
+    let nanos = d.as_nanos() as f64;
+    if nanos >= 1e9 {
+        format!("{:.3}s", nanos / 1e9)
+    } else if nanos >= 1e6 {
+        format!("{:.3}ms", nanos / 1e6)
+    } else if nanos >= 1e3 {
+        format!("{:.3}us", nanos / 1e3)
+    } else {
+        format!("{}ns", nanos)
+    }
+}
+
+// Writes a table with a row per program and engine. The speedup is the run time of the first
+// engine of a program divided by the run time of the engine.
+pub fn write_table(mut w: impl Write, results: &[(PathBuf, Vec<Measurement>)]) -> Result<()> {
+    writeln!(
+        w,
+        "{:<32} {:<14} {:>12} {:>12} {:>8}",
+        "program", "engine", "compile", "run", "speedup"
+    )?;
+    for (path, measurements) in results {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let baseline = measurements.first().map(|m| m.run.as_secs_f64());
+        for m in measurements {
+            let speedup = match baseline {
+                Some(baseline) if m.run.as_secs_f64() > 0.0 => {
+                    format!("{:.2}x", baseline / m.run.as_secs_f64())
+                }
+                _ => "-".to_string(),
+            };
+            writeln!(
+                w,
+                "{:<32} {:<14} {:>12} {:>12} {:>8}",
+                name,
+                m.engine.name(),
+                format_duration(m.compile),
+                format_duration(m.run),
+                speedup
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn measure_test() {
+        let options = Options::default();
+        for engine in Engine::all() {
+            let m = measure(b"++[>+++<-]>.,", b"x", engine, &options, 2).unwrap();
+            assert_eq!(m.engine, engine);
+        }
+        assert!(measure(b",", b"", Engine::Interpreter1, &options, 1).is_err());
+    }
+
+    #[test]
+    fn write_table_test() {
+        let results = vec![(
+            PathBuf::from("benches/a.bf"),
+            vec![
+                Measurement {
+                    engine: Engine::Interpreter1,
+                    compile: Duration::from_micros(12),
+                    run: Duration::from_millis(1500),
+                },
+                Measurement {
+                    engine: Engine::Interpreter3,
+                    compile: Duration::from_nanos(900),
+                    run: Duration::from_millis(500),
+                },
+            ],
+        )];
+        let mut table = Vec::new();
+        write_table(&mut table, &results).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "\
+program                          engine              compile          run  speedup
+a.bf                             interpreter1       12.000us       1.500s    1.00x
+a.bf                             interpreter3          900ns    500.000ms    3.00x
+"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+// A small structured language which compiles to brainfuck, for programs too long to write by
+// hand. Its source files are `.bfm`.
+//
+//     var n digit
+//     set n 3
+//     while n {
+//         copy n digit
+//         add digit '0'
+//         print digit
+//         sub n 1
+//     }
+//     print "\n"
+//
+// There is one statement on each line, and `#` starts a comment. Variables are cells, which start
+// at zero and wrap around like any other cell.
+//
+// - `var a b ...` declares variables, which must be declared before they are used.
+// - `set a x`, `add a x` and `sub a x` set `a` to `x`, or add or subtract it. `x` is a number from
+//   0 to 255, a character in single quotes, or a variable.
+// - `copy a b` sets `b` to `a`.
+// - `read a` reads a byte into `a`, and `print a` writes it. `print "text"` writes the text.
+// - `while a {` ... `}` repeats the block while `a` is not zero, and `if a {` ... `}` runs it once
+//   if `a` is not zero.
+use failure::Fail;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+const KEYWORDS: &[&str] = &[
+    "var", "set", "add", "sub", "copy", "read", "print", "while", "if",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    // 1-based.
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Fail for CompileError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(u8),
+    Text(Vec<u8>),
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Constant(u8),
+    Variable(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Set(usize, Value),
+    Add(usize, Value),
+    Sub(usize, Value),
+    Copy(usize, usize),
+    Read(usize),
+    Print(usize),
+    PrintText(Vec<u8>),
+    While(usize, Vec<Statement>),
+    If(usize, Vec<Statement>),
+}
+
+// Unescapes `\n`, `\t`, `\\`, `\"`, `\'` and `\0`.
+fn unescape(c: u8) -> Option<u8> {
+    match c {
+        b'n' => Some(b'\n'),
+        b't' => Some(b'\t'),
+        b'0' => Some(0),
+        b'\\' | b'"' | b'\'' => Some(c),
+        _ => None,
+    }
+}
+
+fn tokenize(line: &[u8]) -> std::result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        let c = line[i];
+        i += 1;
+        match c {
+            b'#' => break,
+            b'{' => tokens.push(Token::Open),
+            b'}' => tokens.push(Token::Close),
+            b'"' | b'\'' => {
+                let mut text = Vec::new();
+                loop {
+                    match line.get(i) {
+                        None => return Err("unterminated literal".to_string()),
+                        Some(q) if *q == c => break,
+                        Some(b'\\') => {
+                            let escaped = line.get(i + 1).cloned().and_then(unescape);
+                            text.push(escaped.ok_or("invalid escape")?);
+                            i += 1;
+                        }
+                        Some(b) => text.push(*b),
+                    }
+                    i += 1;
+                }
+                i += 1;
+                if c == b'"' {
+                    tokens.push(Token::Text(text));
+                } else if text.len() == 1 {
+                    tokens.push(Token::Number(text[0]));
+                } else {
+                    return Err("a character literal must be one byte".to_string());
+                }
+            }
+            b'0'..=b'9' => {
+                let start = i - 1;
+                while i < line.len() && line[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits = String::from_utf8_lossy(&line[start..i]);
+                let n = digits
+                    .parse()
+                    .map_err(|_| format!("{} is not a number from 0 to 255", digits))?;
+                tokens.push(Token::Number(n));
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = i - 1;
+                while i < line.len() && (line[i].is_ascii_alphanumeric() || line[i] == b'_') {
+                    i += 1;
+                }
+                let word = String::from_utf8_lossy(&line[start..i]).into_owned();
+                tokens.push(Token::Word(word));
+            }
+            c if c.is_ascii_whitespace() => (),
+            c => return Err(format!("unexpected character '{}'", c as char)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    // The non-empty lines, with their line numbers.
+    lines: Vec<(usize, Vec<Token>)>,
+    next: usize,
+    variables: Vec<String>,
+}
+
+impl Parser {
+    fn variable(&self, name: &str) -> std::result::Result<usize, String> {
+        self.variables
+            .iter()
+            .position(|v| v == name)
+            .ok_or_else(|| format!("undeclared variable {}", name))
+    }
+
+    fn value(&self, token: &Token) -> std::result::Result<Value, String> {
+        match token {
+            Token::Number(n) => Ok(Value::Constant(*n)),
+            Token::Word(name) => Ok(Value::Variable(self.variable(name)?)),
+            _ => Err("expected a number, a character or a variable".to_string()),
+        }
+    }
+
+    // Parses statements up to the `}` closing the block opened at `open`, or to the end of the
+    // program if `open` is `None`.
+    fn block(&mut self, open: Option<usize>) -> std::result::Result<Vec<Statement>, CompileError> {
+        let mut statements = Vec::new();
+        while self.next < self.lines.len() {
+            let (line, tokens) = self.lines[self.next].clone();
+            self.next += 1;
+            if tokens == [Token::Close] {
+                return match open {
+                    Some(_) => Ok(statements),
+                    None => Err(CompileError {
+                        line,
+                        message: "unmatched }".to_string(),
+                    }),
+                };
+            }
+            let statement = self
+                .statement(&tokens)
+                .map_err(|message| CompileError { line, message })?;
+            match statement {
+                Some(Statement::While(v, _)) => {
+                    statements.push(Statement::While(v, self.block(Some(line))?))
+                }
+                Some(Statement::If(v, _)) => {
+                    statements.push(Statement::If(v, self.block(Some(line))?))
+                }
+                Some(statement) => statements.push(statement),
+                None => (),
+            }
+        }
+        match open {
+            Some(line) => Err(CompileError {
+                line,
+                message: "unclosed {".to_string(),
+            }),
+            None => Ok(statements),
+        }
+    }
+
+    // Parses one line. The body of `while` and `if` is left empty, for `block` to fill in.
+    fn statement(&mut self, tokens: &[Token]) -> std::result::Result<Option<Statement>, String> {
+        let (keyword, args) = match tokens.split_first() {
+            Some((Token::Word(keyword), args)) => (keyword.as_str(), args),
+            _ => return Err("expected a statement".to_string()),
+        };
+        let usage = |usage: &str| Err(format!("usage: {}", usage));
+        let statement = match (keyword, args) {
+            ("var", names) if !names.is_empty() => {
+                for name in names {
+                    match name {
+                        Token::Word(name) if KEYWORDS.contains(&name.as_str()) => {
+                            return Err(format!("{} is a keyword", name));
+                        }
+                        Token::Word(name) if self.variables.contains(name) => {
+                            return Err(format!("{} is already declared", name));
+                        }
+                        Token::Word(name) => self.variables.push(name.clone()),
+                        _ => return usage("var <name>..."),
+                    }
+                }
+                return Ok(None);
+            }
+            ("var", _) => return usage("var <name>..."),
+            ("set", [Token::Word(a), x]) => Statement::Set(self.variable(a)?, self.value(x)?),
+            ("add", [Token::Word(a), x]) => Statement::Add(self.variable(a)?, self.value(x)?),
+            ("sub", [Token::Word(a), x]) => Statement::Sub(self.variable(a)?, self.value(x)?),
+            ("set", _) | ("add", _) | ("sub", _) => {
+                return usage(&format!("{} <variable> <value>", keyword))
+            }
+            ("copy", [Token::Word(a), Token::Word(b)]) => {
+                Statement::Copy(self.variable(a)?, self.variable(b)?)
+            }
+            ("copy", _) => return usage("copy <from> <to>"),
+            ("read", [Token::Word(a)]) => Statement::Read(self.variable(a)?),
+            ("read", _) => return usage("read <variable>"),
+            ("print", [Token::Word(a)]) => Statement::Print(self.variable(a)?),
+            ("print", [Token::Text(text)]) => Statement::PrintText(text.clone()),
+            ("print", _) => return usage("print <variable> or print \"text\""),
+            ("while", [Token::Word(a), Token::Open]) => {
+                Statement::While(self.variable(a)?, Vec::new())
+            }
+            ("if", [Token::Word(a), Token::Open]) => Statement::If(self.variable(a)?, Vec::new()),
+            ("while", _) | ("if", _) => return usage(&format!("{} <variable> {{", keyword)),
+            (keyword, _) => return Err(format!("unknown statement {}", keyword)),
+        };
+        Ok(Some(statement))
+    }
+}
+
+// Emits brainfuck, keeping track of the data pointer, which is known everywhere as every block
+// ends where it started. Temporary cells are above the variables, and are zero when free.
+struct Emitter {
+    program: Vec<u8>,
+    data_ptr: usize,
+    next_temporary: usize,
+}
+
+impl Emitter {
+    fn emit(&mut self, c: u8, n: usize) {
+        self.program.extend(std::iter::repeat_n(c, n));
+    }
+
+    fn move_to(&mut self, cell: usize) {
+        if cell > self.data_ptr {
+            self.emit(b'>', cell - self.data_ptr);
+        } else {
+            self.emit(b'<', self.data_ptr - cell);
+        }
+        self.data_ptr = cell;
+    }
+
+    fn allocate(&mut self) -> usize {
+        self.next_temporary += 1;
+        self.next_temporary - 1
+    }
+
+    // Frees the last temporary allocated, which must be zero.
+    fn free(&mut self) {
+        self.next_temporary -= 1;
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.move_to(cell);
+        self.program.extend(b"[-]");
+    }
+
+    // Adds `n` to `cell`, by counting down if that is shorter.
+    fn add_constant(&mut self, cell: usize, n: u8) {
+        self.move_to(cell);
+        if n <= 128 {
+            self.emit(b'+', n as usize);
+        } else {
+            self.emit(b'-', 256 - n as usize);
+        }
+    }
+
+    // Adds `from`, or subtracts it if `subtract`, to `to`, which may be `from` itself. `from` is
+    // moved to a temporary, and moved back while it is added.
+    fn add_variable(&mut self, to: usize, from: usize, subtract: bool) {
+        let temporary = self.allocate();
+        self.move_to(from);
+        self.program.push(b'[');
+        self.program.push(b'-');
+        self.move_to(temporary);
+        self.program.push(b'+');
+        self.move_to(from);
+        self.program.push(b']');
+        self.move_to(temporary);
+        self.program.push(b'[');
+        self.program.push(b'-');
+        self.move_to(from);
+        self.program.push(b'+');
+        self.move_to(to);
+        self.program.push(if subtract { b'-' } else { b'+' });
+        self.move_to(temporary);
+        self.program.push(b']');
+        self.free();
+    }
+
+    fn add(&mut self, to: usize, value: Value, subtract: bool) {
+        match value {
+            Value::Constant(n) if subtract => self.add_constant(to, n.wrapping_neg()),
+            Value::Constant(n) => self.add_constant(to, n),
+            Value::Variable(from) => self.add_variable(to, from, subtract),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Set(a, Value::Variable(b)) => self.statement(&Statement::Copy(*b, *a)),
+            Statement::Set(a, value) => {
+                self.clear(*a);
+                self.add(*a, *value, false);
+            }
+            Statement::Add(a, value) => self.add(*a, *value, false),
+            Statement::Sub(a, value) => self.add(*a, *value, true),
+            Statement::Copy(a, b) => {
+                if a != b {
+                    self.clear(*b);
+                    self.add_variable(*b, *a, false);
+                }
+            }
+            Statement::Read(a) => {
+                self.move_to(*a);
+                self.program.push(b',');
+            }
+            Statement::Print(a) => {
+                self.move_to(*a);
+                self.program.push(b'.');
+            }
+            Statement::PrintText(text) => {
+                let temporary = self.allocate();
+                let mut value = 0u8;
+                for b in text {
+                    self.add_constant(temporary, b.wrapping_sub(value));
+                    self.program.push(b'.');
+                    value = *b;
+                }
+                self.clear(temporary);
+                self.free();
+            }
+            Statement::While(a, body) => {
+                self.move_to(*a);
+                self.program.push(b'[');
+                self.block(body);
+                self.move_to(*a);
+                self.program.push(b']');
+            }
+            Statement::If(a, body) => {
+                let flag = self.allocate();
+                self.add_variable(flag, *a, false);
+                self.move_to(flag);
+                self.program.push(b'[');
+                self.block(body);
+                self.clear(flag);
+                self.program.push(b']');
+                self.free();
+            }
+        }
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+}
+
+// Compiles the `.bfm` program `s` to brainfuck. Errors are `CompileError`s.
+pub fn compile(s: &[u8]) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for (i, line) in s.split(|c| *c == b'\n').enumerate() {
+        let tokens = tokenize(line).map_err(|message| CompileError {
+            line: i + 1,
+            message,
+        })?;
+        if !tokens.is_empty() {
+            lines.push((i + 1, tokens));
+        }
+    }
+    let mut parser = Parser {
+        lines,
+        next: 0,
+        variables: Vec::new(),
+    };
+    let statements = parser.block(None)?;
+    let mut emitter = Emitter {
+        program: Vec::new(),
+        data_ptr: 0,
+        next_temporary: parser.variables.len(),
+    };
+    emitter.block(&statements);
+    emitter.program.push(b'\n');
+    Ok(emitter.program)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::brainfuck::*;
+
+    fn run(s: &str, input: &[u8]) -> Vec<u8> {
+        let program = compile(s.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        Engine::Interpreter3
+            .run_on_tape(
+                &program,
+                &Options::default(),
+                input,
+                &mut out,
+                std::io::sink(),
+                &mut Tape::new(Engine::Interpreter3.memory_size()),
+            )
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn compile_test() {
+        let countdown = r#"
+var n digit
+set n 3
+while n {  # prints 321
+    copy n digit
+    add digit '0'
+    print digit
+    sub n 1
+}
+print "\n"
+"#;
+        assert_eq!(run(countdown, b""), b"321\n");
+
+        let arithmetic = "
+var a b
+set a 200
+add a a     # 144
+set b a
+sub b 250   # 150
+sub a b     # 250
+print a
+print b
+add b 'A'
+sub a a
+print b
+print a
+";
+        assert_eq!(run(arithmetic, b""), [250, 150, 215, 0]);
+
+        let echo_unless_zero = "
+var c d
+read c
+read d
+if c {
+    print c
+    if d {
+        print \"&\"
+    }
+    print c
+}
+if d {
+    print d
+}
+";
+        assert_eq!(run(echo_unless_zero, b"xy"), b"x&xy");
+        assert_eq!(run(echo_unless_zero, b"\0y"), b"y");
+        assert_eq!(run(echo_unless_zero, b"x\0"), b"xx");
+    }
+
+    #[test]
+    fn compile_error_test() {
+        let error = |s: &str| {
+            compile(s.as_bytes())
+                .unwrap_err()
+                .downcast::<CompileError>()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(error("var a\nset b 1"), "line 2: undeclared variable b");
+        assert_eq!(
+            error("var a\nadd a 256"),
+            "line 2: 256 is not a number from 0 to 255"
+        );
+        assert_eq!(error("var a a"), "line 1: a is already declared");
+        assert_eq!(error("var while"), "line 1: while is a keyword");
+        assert_eq!(error("var a\nwhile a {\n"), "line 2: unclosed {");
+        assert_eq!(error("}"), "line 1: unmatched }");
+        assert_eq!(error("print \"a"), "line 1: unterminated literal");
+        assert_eq!(error("copy a"), "line 1: usage: copy <from> <to>");
+        assert_eq!(error("goto a"), "line 1: unknown statement goto");
+    }
+}
@@ -0,0 +1,496 @@
+// The bit-level relatives of brainfuck, whose cells hold a single bit. Programs are translated to
+// the `Op`s of the optimized interpreters, so they get the same loop optimizations, bounds checks
+// and `#` dumps, and are run with bit semantics. The profiler, tracer and coverage run them one
+// instruction at a time on `BitInterpreter` instead, like brainfuck on `Interpreter1`.
+//
+// https://esolangs.org/wiki/Boolfuck
+// https://esolangs.org/wiki/Smallfuck
+use crate::brainfuck::*;
+use crate::scan::scan_loop;
+use std::io::Read;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    // `+` flips the bit, `,` reads a bit and `;` writes one. Bytes are read and written least
+    // significant bit first.
+    Boolfuck,
+    // `*` flips the bit, and there is no I/O. The program ends when the pointer moves off the tape.
+    Smallfuck,
+}
+
+impl Language {
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Boolfuck => "boolfuck",
+            Language::Smallfuck => "smallfuck",
+        }
+    }
+
+    // The brainfuck instruction standing for `c` in the `Op`s, where `+` flips and `.` writes a
+    // bit.
+    fn instruction(self, c: u8, options: &Options) -> Option<u8> {
+        match (self, c) {
+            (_, b'>') | (_, b'<') | (_, b'[') | (_, b']') => Some(c),
+            (_, b'#') if options.debug_hash => Some(c),
+            (Language::Boolfuck, b'+') | (Language::Smallfuck, b'*') => Some(b'+'),
+            (Language::Boolfuck, b',') => Some(b','),
+            (Language::Boolfuck, b';') => Some(b'.'),
+            _ => None,
+        }
+    }
+
+    // The instructions of `s`, as brainfuck, with the offset of each in `s`.
+    pub(crate) fn filter_instructions(self, s: &[u8], options: &Options) -> (Vec<u8>, Vec<usize>) {
+        s.iter()
+            .enumerate()
+            .filter_map(|(i, c)| self.instruction(*c, options).map(|c| (c, i)))
+            .unzip()
+    }
+
+    // Runs `s` from the state in `tape`, like `Engine::run_on_tape`. Each cell of `tape` holds 0 or
+    // 1.
+    pub fn run_on_tape<R: Read, W: Write, D: Write>(
+        self,
+        s: &[u8],
+        options: &Options,
+        r: R,
+        w: W,
+        d: D,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let (instructions, positions) = self.filter_instructions(s, options);
+        check_brackets(&instructions).map_err(|e| e.at(&positions))?;
+        match self {
+            Language::Boolfuck => Boolfuck::with_options(s, options).run_on_tape(r, w, d, tape),
+            Language::Smallfuck => Smallfuck::with_options(s, options).run_on_tape(r, w, d, tape),
+        }
+    }
+}
+
+impl Language {
+    // The result of a run which stopped with `result`. A Smallfuck program ends when the pointer
+    // moves off the tape.
+    fn end(self, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e)
+                if self == Language::Smallfuck
+                    && e.downcast_ref() == Some(&RuntimeError::PointerOutOfBounds) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Language {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Language> {
+        [Language::Boolfuck, Language::Smallfuck]
+            .iter()
+            .cloned()
+            .find(|language| language.name() == s)
+            .ok_or_else(|| failure::format_err!("unknown bit language: {}", s))
+    }
+}
+
+struct BitProgram {
+    language: Language,
+    ops: Vec<Op>,
+    eof: EofPolicy,
+}
+
+// Bits read by `,`, taken from bytes.
+struct BitReader<R: Read> {
+    input: std::io::Bytes<R>,
+    // The byte being read, and the number of its bits which are left.
+    byte: u8,
+    bits: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(read: R) -> BitReader<R> {
+        BitReader {
+            input: input_bytes(read),
+            byte: 0,
+            bits: 0,
+        }
+    }
+
+    // Reads a bit into `cell`. At the end of input, `eof` is applied to the bit.
+    fn read_bit(&mut self, cell: &mut u8, eof: EofPolicy) -> Result<()> {
+        if self.bits == 0 {
+            match self.input.next() {
+                Some(b) => {
+                    self.byte = b?;
+                    self.bits = 8;
+                }
+                None => {
+                    eof.read_eof(cell)?;
+                    *cell &= 1;
+                    return Ok(());
+                }
+            }
+        }
+        *cell = self.byte & 1;
+        self.byte >>= 1;
+        self.bits -= 1;
+        Ok(())
+    }
+}
+
+// Bits written by `;`, gathered into bytes.
+struct BitWriter<W: Write> {
+    write: W,
+    byte: u8,
+    bits: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(write: W) -> BitWriter<W> {
+        BitWriter {
+            write,
+            byte: 0,
+            bits: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: u8) -> Result<()> {
+        self.byte |= bit << self.bits;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Writes the pending bits, if any, padded with zeros.
+    fn flush(&mut self) -> Result<()> {
+        if self.bits > 0 {
+            self.write.write_all(&[self.byte])?;
+            self.write.flush()?;
+            self.byte = 0;
+            self.bits = 0;
+        }
+        Ok(())
+    }
+}
+
+impl BitProgram {
+    fn new(s: &[u8], language: Language, options: &Options) -> BitProgram {
+        let (instructions, _) = language.filter_instructions(s, options);
+        BitProgram {
+            language,
+            ops: translate_program(&instructions, true),
+            eof: options.eof,
+        }
+    }
+
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let mut writer = BitWriter::new(write);
+        let result = self.run_ops(read, &mut writer, debug, tape);
+        writer.flush()?;
+        self.language.end(result)
+    }
+
+    fn run_ops(
+        &self,
+        read: impl Read,
+        writer: &mut BitWriter<impl Write>,
+        mut debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let mut pc = 0;
+        let Tape { memory, data_ptr } = tape;
+        let mut reader = BitReader::new(read);
+        while pc < self.ops.len() {
+            match self.ops[pc] {
+                Op::IncPtr(n) => move_ptr(data_ptr, n as isize, memory.len())?,
+                Op::DecPtr(n) => move_ptr(data_ptr, -(n as isize), memory.len())?,
+                Op::IncData(n) => memory[*data_ptr] ^= (n % 2) as u8,
+                Op::ReadStdin(n) => {
+                    for _ in 0..n {
+                        reader.read_bit(&mut memory[*data_ptr], self.eof)?;
+                    }
+                }
+                Op::WriteStdout(n) => {
+                    for _ in 0..n {
+                        writer.write_bit(memory[*data_ptr])?;
+                    }
+                }
+                Op::JumpIfDataIsZero(n) => {
+                    if memory[*data_ptr] == 0 {
+                        pc = n;
+                    }
+                }
+                Op::JumpIfDataIsNotZero(n) => {
+                    if memory[*data_ptr] != 0 {
+                        pc = n;
+                    }
+                }
+                Op::LoopSetToZero => memory[*data_ptr] = 0,
+                Op::LoopMovePtr(n) => scan_loop(memory, data_ptr, n)?,
+                Op::DebugDump(n) => dump_state(&mut debug, n, *data_ptr, memory)?,
+                // There is no `-` or extension to make these from.
+                Op::DecData(_)
+                | Op::LoopMoveData(_)
+                | Op::DefineProcedure(_)
+                | Op::EndProcedure
+                | Op::CallProcedure
+                | Op::Fork
+                | Op::End
+                | Op::Store
+                | Op::Load
+                | Op::ShiftLeft(_)
+                | Op::ShiftRight(_)
+                | Op::Not
+                | Op::Xor
+                | Op::And
+                | Op::Or => unreachable!(),
+            }
+            pc += 1;
+        }
+        Ok(())
+    }
+}
+
+// Runs the instructions of a bit language one at a time, as `Interpreter1` does for brainfuck, so
+// that an `Observer` sees each of them.
+pub(crate) struct BitInterpreter {
+    language: Language,
+    instructions: Vec<u8>,
+    jumptable: Vec<usize>,
+    eof: EofPolicy,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl BitInterpreter {
+    // An interpreter of `instructions`, as given by `Language::filter_instructions`.
+    pub(crate) fn from_instructions(
+        language: Language,
+        instructions: Vec<u8>,
+        options: &Options,
+    ) -> BitInterpreter {
+        BitInterpreter {
+            language,
+            jumptable: Interpreter1::from_instructions(instructions.clone(), options)
+                .create_jumptable(),
+            instructions,
+            eof: options.eof,
+            cancel: options.cancel.clone(),
+        }
+    }
+
+    pub(crate) fn create_jumptable(&self) -> Vec<usize> {
+        self.jumptable.clone()
+    }
+
+    pub(crate) fn run_observed(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+        observer: &mut impl Observer,
+    ) -> Result<()> {
+        let mut writer = BitWriter::new(write);
+        let result = self.run_instructions(read, &mut writer, debug, tape, observer);
+        writer.flush()?;
+        self.language.end(result)
+    }
+
+    fn run_instructions(
+        &self,
+        read: impl Read,
+        writer: &mut BitWriter<impl Write>,
+        mut debug: impl Write,
+        tape: &mut Tape,
+        observer: &mut impl Observer,
+    ) -> Result<()> {
+        let jumptable = &self.jumptable;
+        let mut pc = 0;
+        let Tape { memory, data_ptr } = tape;
+        let mut reader = BitReader::new(read);
+        while pc < self.instructions.len() {
+            observer.instruction(pc);
+            match self.instructions[pc] {
+                b'>' => move_ptr(data_ptr, 1, memory.len())?,
+                b'<' => move_ptr(data_ptr, -1, memory.len())?,
+                b'+' => memory[*data_ptr] ^= 1,
+                b',' => {
+                    reader.read_bit(&mut memory[*data_ptr], self.eof)?;
+                    observer.input(pc, memory[*data_ptr]);
+                }
+                b'.' => {
+                    writer.write_bit(memory[*data_ptr])?;
+                    observer.output(pc, memory[*data_ptr]);
+                }
+                b'[' => {
+                    if memory[*data_ptr] == 0 {
+                        pc = jumptable[pc];
+                    } else {
+                        observer.loop_enter(pc);
+                    }
+                }
+                b']' => {
+                    if memory[*data_ptr] != 0 {
+                        check_cancel(self.cancel.as_deref())?;
+                        pc = jumptable[pc];
+                    } else {
+                        observer.loop_exit(jumptable[pc]);
+                    }
+                }
+                b'#' => dump_state(&mut debug, pc, *data_ptr, memory)?,
+                _ => unreachable!(),
+            }
+            pc += 1;
+        }
+        Ok(())
+    }
+}
+
+pub struct Boolfuck(BitProgram);
+
+impl Brainfuck for Boolfuck {
+    // As many bits as brainfuck has bytes.
+    const MEMORY_SIZE: usize = 30000 * 8;
+
+    fn with_options(s: &[u8], options: &Options) -> Boolfuck {
+        Boolfuck(BitProgram::new(s, Language::Boolfuck, options))
+    }
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        self.0.run_on_tape(read, write, debug, tape)
+    }
+}
+
+pub struct Smallfuck(BitProgram);
+
+impl Brainfuck for Smallfuck {
+    fn with_options(s: &[u8], options: &Options) -> Smallfuck {
+        Smallfuck(BitProgram::new(s, Language::Smallfuck, options))
+    }
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        self.0.run_on_tape(read, write, debug, tape)
+    }
+}
+
+impl Language {
+    pub fn memory_size(self) -> usize {
+        match self {
+            Language::Boolfuck => Boolfuck::MEMORY_SIZE,
+            Language::Smallfuck => Smallfuck::MEMORY_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn boolfuck_test() {
+        // Echoes a byte, bit by bit.
+        let cat = b",>,>,>,>,>,>,>,<<<<<<<;>;>;>;>;>;>;>;";
+        let mut out = Vec::new();
+        Boolfuck::new(cat).run(&b"Hi"[..], &mut out).unwrap();
+        assert_eq!(out, b"H");
+
+        // Pending bits are written at the end, padded with zeros.
+        let mut out = Vec::new();
+        Boolfuck::new(b"+;;+;").run(&[][..], &mut out).unwrap();
+        assert_eq!(out, b"\x03");
+
+        let options = Options {
+            eof: EofPolicy::MinusOne,
+            ..Options::default()
+        };
+        let mut tape = Tape::new(4);
+        Language::Boolfuck
+            .run_on_tape(
+                b",>,>+[+]<++",
+                &options,
+                &[][..],
+                std::io::sink(),
+                std::io::sink(),
+                &mut tape,
+            )
+            .unwrap();
+        assert_eq!(
+            tape,
+            Tape {
+                memory: vec![1, 1, 0, 0],
+                data_ptr: 1
+            }
+        );
+    }
+
+    #[test]
+    fn smallfuck_test() {
+        let mut tape = Tape::new(4);
+        let s = b"*>*<[*>]>*>*>* comment";
+        Language::Smallfuck
+            .run_on_tape(
+                s,
+                &Options::default(),
+                &[][..],
+                std::io::sink(),
+                std::io::sink(),
+                &mut tape,
+            )
+            .unwrap();
+        assert_eq!(
+            tape,
+            Tape {
+                memory: vec![0, 0, 0, 1],
+                data_ptr: 3
+            }
+        );
+
+        let e = Language::Smallfuck
+            .run_on_tape(
+                b"*[",
+                &Options::default(),
+                &[][..],
+                std::io::sink(),
+                std::io::sink(),
+                &mut tape,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&SyntaxError::UnmatchedOpenBracket(1))
+        );
+    }
+}
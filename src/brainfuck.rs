@@ -1,43 +1,477 @@
 // https://eli.thegreenplace.net/2017/adventures-in-jit-compilation-part-1-an-interpreter.html
 // https://esolangs.org/wiki/Brainfuck
-use failure;
+use crate::closure::Interpreter4;
+use crate::dialect::Dialect;
+use crate::scan::{self, scan_loop};
+use failure::Fail;
 use log::*;
+use serde_derive::*;
 use std::io::Read;
 use std::io::Write;
-
-use mmap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
-pub trait Brainfuck {
-    fn new(s: &[u8]) -> Self;
-    // fn run(&self, read: impl Read, mut write: impl Write) -> Result<()>;
-    fn run(&self, read: impl Read, write: impl Write) -> Result<()>;
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Keep `#` and dump the machine state to the debug sink when it is executed.
+    pub debug_hash: bool,
+    /// What `,` does at the end of input.
+    pub eof: EofPolicy,
+    /// The language programs are written in.
+    pub dialect: Dialect,
+    /// Run the part of a plain brainfuck program before its first input when it is compiled, so
+    /// that a run on a fresh tape starts from the state it leaves.
+    pub partial_eval: bool,
+    /// Set from another thread to stop a run, which then fails with `RuntimeError::Cancelled` the
+    /// next time a loop repeats.
+    pub cancel: Option<Arc<AtomicBool>>,
 }
 
-fn filter_instructions(s: &[u8]) -> Vec<u8> {
-    s.into_iter()
-        .filter(|c| match c {
-            b'>' | b'<' | b'+' | b'-' | b'.' | b',' | b'[' | b']' => true,
-            _ => false,
-        })
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EofPolicy {
+    // Fail with `RuntimeError::InputUnavailable`.
+    #[default]
+    Error,
+    Zero,
+    // Set the cell to 255.
+    MinusOne,
+    Unchanged,
+}
+
+impl EofPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            EofPolicy::Error => "error",
+            EofPolicy::Zero => "zero",
+            EofPolicy::MinusOne => "minus-one",
+            EofPolicy::Unchanged => "unchanged",
+        }
+    }
+
+    // Sets `cell` as `,` does at the end of input.
+    pub(crate) fn read_eof(self, cell: &mut u8) -> std::result::Result<(), RuntimeError> {
+        match self {
+            EofPolicy::Error => return Err(RuntimeError::InputUnavailable),
+            EofPolicy::Zero => *cell = 0,
+            EofPolicy::MinusOne => *cell = 255,
+            EofPolicy::Unchanged => (),
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for EofPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for EofPolicy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<EofPolicy> {
+        [
+            EofPolicy::Error,
+            EofPolicy::Zero,
+            EofPolicy::MinusOne,
+            EofPolicy::Unchanged,
+        ]
+        .iter()
         .cloned()
-        .collect()
+        .find(|eof| eof.name() == s)
+        .ok_or_else(|| failure::format_err!("unknown EOF policy: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuntimeError {
+    InputUnavailable,
+    // The data pointer would move off either end of the tape.
+    PointerOutOfBounds,
+    // `:` called a procedure number which no `(` has defined.
+    UndefinedProcedure(u8),
+    // Procedure calls are nested deeper than `MAX_CALL_DEPTH`.
+    CallStackOverflow,
+    // `Y` would make more than `fork::MAX_THREADS` threads run at once.
+    TooManyThreads,
+    // `Options::cancel` was set.
+    Cancelled,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuntimeError::InputUnavailable => write!(f, "input unavailable"),
+            RuntimeError::PointerOutOfBounds => write!(f, "data pointer out of bounds"),
+            RuntimeError::UndefinedProcedure(n) => write!(f, "undefined procedure {}", n),
+            RuntimeError::CallStackOverflow => {
+                write!(f, "procedure calls nested deeper than {}", MAX_CALL_DEPTH)
+            }
+            RuntimeError::TooManyThreads => {
+                write!(f, "more than {} threads", crate::fork::MAX_THREADS)
+            }
+            RuntimeError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl Fail for RuntimeError {}
+
+impl RuntimeError {
+    pub fn kind(self) -> &'static str {
+        match self {
+            RuntimeError::InputUnavailable => "input-unavailable",
+            RuntimeError::PointerOutOfBounds => "pointer-out-of-bounds",
+            RuntimeError::UndefinedProcedure(_) => "undefined-procedure",
+            RuntimeError::CallStackOverflow => "call-stack-overflow",
+            RuntimeError::TooManyThreads => "too-many-threads",
+            RuntimeError::Cancelled => "cancelled",
+        }
+    }
+}
+
+// The memory of the machine, and the data pointer into it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tape {
+    pub memory: Vec<u8>,
+    pub data_ptr: usize,
+}
+
+impl Tape {
+    pub fn new(size: usize) -> Tape {
+        Tape {
+            memory: vec![0; size],
+            data_ptr: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyntaxError {
+    // The offset of the bracket in the source.
+    UnmatchedOpenBracket(usize),
+    UnmatchedCloseBracket(usize),
+    UnmatchedOpenParen(usize),
+    UnmatchedCloseParen(usize),
+    // A `(` inside the body of another procedure.
+    NestedProcedure(usize),
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyntaxError::UnmatchedOpenBracket(offset) => write!(f, "unmatched '[' at {}", offset),
+            SyntaxError::UnmatchedCloseBracket(offset) => write!(f, "unmatched ']' at {}", offset),
+            SyntaxError::UnmatchedOpenParen(offset) => write!(f, "unmatched '(' at {}", offset),
+            SyntaxError::UnmatchedCloseParen(offset) => write!(f, "unmatched ')' at {}", offset),
+            SyntaxError::NestedProcedure(offset) => {
+                write!(f, "procedure defined inside a procedure at {}", offset)
+            }
+        }
+    }
+}
+
+impl Fail for SyntaxError {}
+
+impl SyntaxError {
+    pub fn kind(self) -> &'static str {
+        match self {
+            SyntaxError::UnmatchedOpenBracket(_) => "unmatched-open-bracket",
+            SyntaxError::UnmatchedCloseBracket(_) => "unmatched-close-bracket",
+            SyntaxError::UnmatchedOpenParen(_) => "unmatched-open-paren",
+            SyntaxError::UnmatchedCloseParen(_) => "unmatched-close-paren",
+            SyntaxError::NestedProcedure(_) => "nested-procedure",
+        }
+    }
+
+    // The same error, with its offset `i` replaced by `positions[i]`.
+    pub(crate) fn at(self, positions: &[usize]) -> SyntaxError {
+        match self {
+            SyntaxError::UnmatchedOpenBracket(i) => SyntaxError::UnmatchedOpenBracket(positions[i]),
+            SyntaxError::UnmatchedCloseBracket(i) => {
+                SyntaxError::UnmatchedCloseBracket(positions[i])
+            }
+            SyntaxError::UnmatchedOpenParen(i) => SyntaxError::UnmatchedOpenParen(positions[i]),
+            SyntaxError::UnmatchedCloseParen(i) => SyntaxError::UnmatchedCloseParen(positions[i]),
+            SyntaxError::NestedProcedure(i) => SyntaxError::NestedProcedure(positions[i]),
+        }
+    }
+}
+
+// A short name of the kind of `e`, used to compare errors between engines and in test cases.
+pub fn error_kind(e: &failure::Error) -> String {
+    if let Some(e) = e.downcast_ref::<RuntimeError>() {
+        e.kind().to_string()
+    } else if let Some(e) = e.downcast_ref::<SyntaxError>() {
+        e.kind().to_string()
+    } else {
+        e.to_string()
+    }
+}
+
+pub fn check_brackets(s: &[u8]) -> std::result::Result<(), SyntaxError> {
+    // The offsets of the `[` and `(` which are still open. A loop may not cross the bounds of a
+    // procedure.
+    let mut open = Vec::new();
+    for (offset, c) in s.iter().enumerate() {
+        match c {
+            b'[' => open.push(offset),
+            b']' => match open.pop() {
+                Some(start) if s[start] == b'[' => (),
+                _ => return Err(SyntaxError::UnmatchedCloseBracket(offset)),
+            },
+            b'(' => {
+                if open.iter().any(|start| s[*start] == b'(') {
+                    return Err(SyntaxError::NestedProcedure(offset));
+                }
+                open.push(offset);
+            }
+            b')' => match open.pop() {
+                Some(start) if s[start] == b'(' => (),
+                Some(start) => return Err(SyntaxError::UnmatchedOpenBracket(start)),
+                None => return Err(SyntaxError::UnmatchedCloseParen(offset)),
+            },
+            _ => (),
+        }
+    }
+    match open.first() {
+        Some(offset) if s[*offset] == b'(' => Err(SyntaxError::UnmatchedOpenParen(*offset)),
+        Some(offset) => Err(SyntaxError::UnmatchedOpenBracket(*offset)),
+        None => Ok(()),
+    }
+}
+
+// Engines expect the brackets of a program to be balanced, and panic otherwise. `Engine` checks
+// them first.
+pub trait Brainfuck {
+    // The size of the tape used by `run`.
+    const MEMORY_SIZE: usize = 30000;
+
+    fn new(s: &[u8]) -> Self
+    where
+        Self: Sized,
+    {
+        Self::with_options(s, &Options::default())
+    }
+    fn with_options(s: &[u8], options: &Options) -> Self;
+    fn run(&self, read: impl Read, write: impl Write) -> Result<()> {
+        self.run_with_debug(read, write, std::io::stderr())
+    }
+    // `debug` receives the output of `#`, separately from the program's own output.
+    fn run_with_debug(&self, read: impl Read, write: impl Write, debug: impl Write) -> Result<()> {
+        self.run_on_tape(read, write, debug, &mut Tape::new(Self::MEMORY_SIZE))
+    }
+    // Runs the program starting from the state in `tape`, and leaves the final state there. If
+    // the program fails, `tape` is the state at the failing instruction.
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()>;
+}
+
+// The brainfuck instructions of `s`, which is in the dialect of `options`.
+pub(crate) fn filter_instructions(s: &[u8], options: &Options) -> Vec<u8> {
+    filter_instructions_with_positions(s, options).0
+}
+
+// Same as `filter_instructions`, but also returns the offset in `s` of each instruction.
+pub(crate) fn filter_instructions_with_positions(
+    s: &[u8],
+    options: &Options,
+) -> (Vec<u8>, Vec<usize>) {
+    options.dialect.tokenize(s, options.debug_hash)
+}
+
+// `check_brackets` on the instructions of `s`, with the offsets of its errors in `s`.
+pub(crate) fn check_program_brackets(
+    s: &[u8],
+    options: &Options,
+) -> std::result::Result<(), SyntaxError> {
+    let (instructions, positions) = filter_instructions_with_positions(s, options);
+    check_brackets(&instructions).map_err(|e| e.at(&positions))
 }
 
-struct Interpreter1 {
+// Hooks into `Interpreter1` and `bit::BitInterpreter`, whose pc is the index of an instruction in
+// the filtered program.
+// Loops are identified by the pc of their `[`.
+pub(crate) trait Observer {
+    fn instruction(&mut self, _pc: usize) {}
+    fn loop_enter(&mut self, _loop_start: usize) {}
+    fn loop_exit(&mut self, _loop_start: usize) {}
+    fn input(&mut self, _pc: usize, _byte: u8) {}
+    fn output(&mut self, _pc: usize, _byte: u8) {}
+}
+
+impl Observer for () {}
+
+// Moves the data pointer by `offset` on a tape of `len` cells. A pointer which would leave the tape
+// stops at its end, as if it had moved one cell at a time, so that every engine fails in the same
+// state however it folds moves.
+#[inline]
+pub(crate) fn move_ptr(
+    data_ptr: &mut usize,
+    offset: isize,
+    len: usize,
+) -> std::result::Result<(), RuntimeError> {
+    // A pointer moved below 0 wraps around to a huge value.
+    let moved = data_ptr.wrapping_add(offset as usize);
+    if moved < len {
+        *data_ptr = moved;
+        Ok(())
+    } else {
+        *data_ptr = if offset < 0 { 0 } else { len - 1 };
+        Err(RuntimeError::PointerOutOfBounds)
+    }
+}
+
+// Fails if the run has been cancelled through `Options::cancel`. Engines check it each time a loop
+// repeats, which is often enough to stop a program which never ends.
+#[inline]
+pub(crate) fn check_cancel(cancel: Option<&AtomicBool>) -> std::result::Result<(), RuntimeError> {
+    match cancel {
+        Some(cancel) if cancel.load(Ordering::Relaxed) => Err(RuntimeError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+// Reads one byte into `cell`, following `eof` at the end of input.
+pub(crate) fn read_cell(
+    input: &mut impl Iterator<Item = std::io::Result<u8>>,
+    cell: &mut u8,
+    eof: EofPolicy,
+) -> Result<()> {
+    match input.next() {
+        Some(byte) => *cell = byte?,
+        None => eof.read_eof(cell)?,
+    }
+    Ok(())
+}
+
+// Whether `instructions` are all brainfuck's own.
+fn is_plain_brainfuck(instructions: &[u8]) -> bool {
+    instructions.iter().all(|c| b"><+-.,[]".contains(c))
+}
+
+// Maximum number of pbrain procedure calls in progress at once.
+pub const MAX_CALL_DEPTH: usize = 4096;
+
+// The procedures of pbrain defined so far, by number, and the calls in progress. `(` defines the
+// procedure numbered by the current cell, and `:` calls it. Procedures are identified by the pc of
+// their `(`, and a call returns to the pc of its `:`.
+// https://esolangs.org/wiki/Pbrain
+#[derive(Clone)]
+pub(crate) struct Procedures {
+    starts: Vec<Option<usize>>,
+    calls: Vec<usize>,
+}
+
+impl Procedures {
+    pub(crate) fn new() -> Procedures {
+        Procedures {
+            starts: vec![None; 256],
+            calls: Vec::new(),
+        }
+    }
+
+    pub(crate) fn define(&mut self, number: u8, start: usize) {
+        self.starts[number as usize] = Some(start);
+    }
+
+    // Returns the start of procedure `number`, which is to return to `pc`.
+    pub(crate) fn call(
+        &mut self,
+        number: u8,
+        pc: usize,
+    ) -> std::result::Result<usize, RuntimeError> {
+        let start = self.starts[number as usize].ok_or(RuntimeError::UndefinedProcedure(number))?;
+        if self.calls.len() == MAX_CALL_DEPTH {
+            return Err(RuntimeError::CallStackOverflow);
+        }
+        self.calls.push(pc);
+        Ok(start)
+    }
+
+    // Returns the pc to return to from the innermost call. A `)` is only reached in a call, as
+    // defining a procedure skips its body.
+    pub(crate) fn ret(&mut self) -> usize {
+        self.calls.pop().expect("')' outside of a call")
+    }
+}
+
+// Number of cells shown on each side of the data pointer by `#`.
+const DEBUG_DUMP_WINDOW: usize = 8;
+
+pub(crate) fn dump_state(
+    mut debug: impl Write,
+    pc: usize,
+    data_ptr: usize,
+    memory: &[u8],
+) -> Result<()> {
+    let start = data_ptr.saturating_sub(DEBUG_DUMP_WINDOW);
+    let end = std::cmp::min(data_ptr + DEBUG_DUMP_WINDOW + 1, memory.len());
+    write!(
+        debug,
+        "#: pc={} ptr={} cells[{}..{}]:",
+        pc, data_ptr, start, end
+    )?;
+    for (i, cell) in memory[start..end].iter().enumerate() {
+        if start + i == data_ptr {
+            write!(debug, " [{}]", cell)?;
+        } else {
+            write!(debug, " {}", cell)?;
+        }
+    }
+    writeln!(debug)?;
+    debug.flush()?;
+    Ok(())
+}
+
+// The bytes of the input of a run. `read` is not buffered here, so that a run takes no more from
+// the caller's reader than `,` consumes. Callers who want buffering wrap `read` themselves.
+#[allow(clippy::unbuffered_bytes)]
+pub(crate) fn input_bytes<R: Read>(read: R) -> std::io::Bytes<R> {
+    read.bytes()
+}
+
+pub(crate) struct Interpreter1 {
     instructions: Vec<u8>,
+    eof: EofPolicy,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl Interpreter1 {
-    fn create_jumptable(&self) -> Vec<usize> {
+    // An interpreter of `instructions`, which are already filtered.
+    pub(crate) fn from_instructions(instructions: Vec<u8>, options: &Options) -> Interpreter1 {
+        Interpreter1 {
+            instructions,
+            eof: options.eof,
+            cancel: options.cancel.clone(),
+        }
+    }
+
+    pub(crate) fn create_jumptable(&self) -> Vec<usize> {
         let mut pc = 0;
         let program_size = self.instructions.len();
 
         let mut jumptable = vec![0; program_size];
 
         while pc < program_size {
-            if self.instructions[pc] == b'[' {
+            if self.instructions[pc] == b'(' {
+                // Procedures are never nested.
+                let end = pc
+                    + self.instructions[pc..]
+                        .iter()
+                        .position(|c| *c == b')')
+                        .unwrap();
+                jumptable[pc] = end;
+                jumptable[end] = pc;
+            } else if self.instructions[pc] == b'[' {
                 let mut bracket_nesting = 1;
                 let mut seek = pc;
                 while bracket_nesting > 0 && seek + 1 < program_size {
@@ -58,48 +492,71 @@ impl Interpreter1 {
     }
 }
 
-impl Brainfuck for Interpreter1 {
-    fn new(s: &[u8]) -> Interpreter1 {
-        Interpreter1 {
-            instructions: filter_instructions(s),
-        }
-    }
-
-    fn run(&self, read: impl Read, mut write: impl Write) -> Result<()> {
-        let mut memory: Vec<u8> = vec![0; 30000];
+impl Interpreter1 {
+    pub(crate) fn run_observed(
+        &self,
+        read: impl Read,
+        mut write: impl Write,
+        mut debug: impl Write,
+        tape: &mut Tape,
+        observer: &mut impl Observer,
+    ) -> Result<()> {
         let jumptable = self.create_jumptable();
 
         let mut pc = 0;
-        let mut data_ptr: usize = 0;
+        let Tape { memory, data_ptr } = tape;
 
-        let mut input = read.bytes();
+        let mut input = input_bytes(read);
+        let mut procedures = Procedures::new();
+        let mut storage = 0;
 
         while pc < self.instructions.len() {
+            observer.instruction(pc);
             match self.instructions[pc] {
-                b'>' => data_ptr += 1,
-                b'<' => data_ptr -= 1,
-                b'+' => memory[data_ptr] = memory[data_ptr].wrapping_add(1),
-                b'-' => memory[data_ptr] = memory[data_ptr].wrapping_sub(1),
+                b'>' => move_ptr(data_ptr, 1, memory.len())?,
+                b'<' => move_ptr(data_ptr, -1, memory.len())?,
+                b'+' => memory[*data_ptr] = memory[*data_ptr].wrapping_add(1),
+                b'-' => memory[*data_ptr] = memory[*data_ptr].wrapping_sub(1),
                 b',' => {
-                    memory[data_ptr] = input
-                        .next()
-                        .ok_or_else(|| failure::err_msg("input unavailable"))?
-                        .expect("read error");
+                    read_cell(&mut input, &mut memory[*data_ptr], self.eof)?;
+                    observer.input(pc, memory[*data_ptr]);
                 }
                 b'.' => {
-                    write.write_all(&memory[data_ptr..(data_ptr + 1)])?;
+                    write.write_all(&memory[*data_ptr..(*data_ptr + 1)])?;
                     write.flush().unwrap();
+                    observer.output(pc, memory[*data_ptr]);
                 }
                 b'[' => {
-                    if memory[data_ptr] == 0 {
+                    if memory[*data_ptr] == 0 {
                         pc = jumptable[pc];
+                    } else {
+                        observer.loop_enter(pc);
                     }
                 }
                 b']' => {
-                    if memory[data_ptr] != 0 {
+                    if memory[*data_ptr] != 0 {
+                        check_cancel(self.cancel.as_deref())?;
                         pc = jumptable[pc];
+                    } else {
+                        observer.loop_exit(jumptable[pc]);
                     }
                 }
+                b'(' => {
+                    procedures.define(memory[*data_ptr], pc);
+                    pc = jumptable[pc];
+                }
+                b')' => pc = procedures.ret(),
+                b':' => pc = procedures.call(memory[*data_ptr], pc)?,
+                b'@' => break,
+                b'$' => storage = memory[*data_ptr],
+                b'!' => memory[*data_ptr] = storage,
+                b'{' => memory[*data_ptr] <<= 1,
+                b'}' => memory[*data_ptr] >>= 1,
+                b'~' => memory[*data_ptr] = !memory[*data_ptr],
+                b'^' => memory[*data_ptr] ^= storage,
+                b'&' => memory[*data_ptr] &= storage,
+                b'|' => memory[*data_ptr] |= storage,
+                b'#' => dump_state(&mut debug, pc, *data_ptr, memory)?,
                 _ => unreachable!(),
             }
             pc += 1;
@@ -108,8 +565,25 @@ impl Brainfuck for Interpreter1 {
     }
 }
 
+impl Brainfuck for Interpreter1 {
+    fn with_options(s: &[u8], options: &Options) -> Interpreter1 {
+        Interpreter1::from_instructions(filter_instructions(s, options), options)
+    }
+
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        self.run_observed(read, write, debug, tape, &mut ())
+    }
+}
+
 // Optimized interpreter Part 1 - take 2
-enum Op {
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
     // For take 2
     IncPtr(usize),
     DecPtr(usize),
@@ -123,12 +597,48 @@ enum Op {
     LoopSetToZero,
     LoopMovePtr(isize),
     LoopMoveData(isize),
+    // `#`, with the index of the instruction in the filtered program so that every tier reports
+    // the same pc. It is never folded, and a loop containing it is never optimized.
+    DebugDump(usize),
+    // pbrain's `(`, with the offset of its `)`, and `)` and `:`. Calls are never folded.
+    DefineProcedure(usize),
+    EndProcedure,
+    CallProcedure,
+    // Brainfork's `Y`, which only `fork` runs.
+    Fork,
+    // Extended Brainfuck Type I. Shifts are folded into one op by their length, `~` and `^` into
+    // one op or none, and the others, which are idempotent, into one op.
+    End,
+    Store,
+    Load,
+    ShiftLeft(usize),
+    ShiftRight(usize),
+    Not,
+    Xor,
+    And,
+    Or,
 }
 
-fn translate_program(instructions: &[u8], do_optimize_loop: bool) -> Vec<Op> {
+// The current cell after `op`, which is one of the ops of Extended Brainfuck Type I which change
+// it.
+#[inline]
+pub(crate) fn apply_bitwise(op: &Op, cell: u8, storage: u8) -> u8 {
+    match *op {
+        Op::ShiftLeft(n) => cell.checked_shl(n as u32).unwrap_or(0),
+        Op::ShiftRight(n) => cell.checked_shr(n as u32).unwrap_or(0),
+        Op::Not => !cell,
+        Op::Xor => cell ^ storage,
+        Op::And => cell & storage,
+        Op::Or => cell | storage,
+        _ => unreachable!(),
+    }
+}
+
+pub(crate) fn translate_program(instructions: &[u8], do_optimize_loop: bool) -> Vec<Op> {
     let mut ops: Vec<Op> = Vec::new();
 
     let mut open_bracket_stack: Vec<usize> = Vec::new();
+    let mut open_procedure = 0;
 
     let mut pc = 0;
     let instructions_size = instructions.len();
@@ -166,7 +676,29 @@ fn translate_program(instructions: &[u8], do_optimize_loop: bool) -> Vec<Op> {
                     }
                     ops.push(Op::JumpIfDataIsNotZero(open_bracket_offset));
                 }
-                pc += 1;;
+                pc += 1;
+            }
+            b'#' => {
+                ops.push(Op::DebugDump(pc));
+                pc += 1;
+            }
+            b'(' => {
+                open_procedure = ops.len();
+                ops.push(Op::DefineProcedure(0));
+                pc += 1;
+            }
+            b')' => {
+                ops[open_procedure] = Op::DefineProcedure(ops.len());
+                ops.push(Op::EndProcedure);
+                pc += 1;
+            }
+            b':' => {
+                ops.push(Op::CallProcedure);
+                pc += 1;
+            }
+            b'Y' => {
+                ops.push(Op::Fork);
+                pc += 1;
             }
             x => {
                 let start = pc;
@@ -182,6 +714,16 @@ fn translate_program(instructions: &[u8], do_optimize_loop: bool) -> Vec<Op> {
                     b'-' => ops.push(Op::DecData(repeats)),
                     b',' => ops.push(Op::ReadStdin(repeats)),
                     b'.' => ops.push(Op::WriteStdout(repeats)),
+                    b'@' => ops.push(Op::End),
+                    b'$' => ops.push(Op::Store),
+                    b'!' => ops.push(Op::Load),
+                    b'{' => ops.push(Op::ShiftLeft(repeats)),
+                    b'}' => ops.push(Op::ShiftRight(repeats)),
+                    b'~' if repeats % 2 == 1 => ops.push(Op::Not),
+                    b'^' if repeats % 2 == 1 => ops.push(Op::Xor),
+                    b'~' | b'^' => (),
+                    b'&' => ops.push(Op::And),
+                    b'|' => ops.push(Op::Or),
                     _ => unreachable!(),
                 }
             }
@@ -192,49 +734,76 @@ fn translate_program(instructions: &[u8], do_optimize_loop: bool) -> Vec<Op> {
 
 struct Interpreter2 {
     ops: Vec<Op>,
+    eof: EofPolicy,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl Brainfuck for Interpreter2 {
-    fn new(s: &[u8]) -> Interpreter2 {
-        let instructions = filter_instructions(s);
+    fn with_options(s: &[u8], options: &Options) -> Interpreter2 {
+        let instructions = filter_instructions(s, options);
         let ops = translate_program(&instructions, false);
-        Interpreter2 { ops }
+        Interpreter2 {
+            ops,
+            eof: options.eof,
+            cancel: options.cancel.clone(),
+        }
     }
-    fn run(&self, read: impl Read, mut write: impl Write) -> Result<()> {
-        let mut memory: Vec<u8> = vec![0; 30000];
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        mut write: impl Write,
+        mut debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
         let mut pc = 0;
-        let mut data_ptr: usize = 0;
-        let mut input = read.bytes();
+        let Tape { memory, data_ptr } = tape;
+        let mut input = input_bytes(read);
+        let mut procedures = Procedures::new();
+        let mut storage = 0;
         while pc < self.ops.len() {
             match self.ops[pc] {
-                Op::IncPtr(n) => data_ptr += n,
-                Op::DecPtr(n) => data_ptr -= n,
-                Op::IncData(n) => memory[data_ptr] = memory[data_ptr].wrapping_add(n as u8),
-                Op::DecData(n) => memory[data_ptr] = memory[data_ptr].wrapping_sub(n as u8),
+                Op::IncPtr(n) => move_ptr(data_ptr, n as isize, memory.len())?,
+                Op::DecPtr(n) => move_ptr(data_ptr, -(n as isize), memory.len())?,
+                Op::IncData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_add(n as u8),
+                Op::DecData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_sub(n as u8),
                 Op::ReadStdin(n) => {
                     for _ in 0..n {
-                        memory[data_ptr] = input
-                            .next()
-                            .ok_or_else(|| failure::err_msg("input unavailable"))?
-                            .expect("read error");
+                        read_cell(&mut input, &mut memory[*data_ptr], self.eof)?;
                     }
                 }
                 Op::WriteStdout(n) => {
                     for _ in 0..n {
-                        write.write_all(&memory[data_ptr..(data_ptr + 1)])?;
+                        write.write_all(&memory[*data_ptr..(*data_ptr + 1)])?;
                     }
                     write.flush().unwrap();
                 }
                 Op::JumpIfDataIsZero(n) => {
-                    if memory[data_ptr] == 0 {
+                    if memory[*data_ptr] == 0 {
                         pc = n;
                     }
                 }
                 Op::JumpIfDataIsNotZero(n) => {
-                    if memory[data_ptr] != 0 {
+                    if memory[*data_ptr] != 0 {
+                        check_cancel(self.cancel.as_deref())?;
                         pc = n;
                     }
                 }
+                Op::DebugDump(n) => dump_state(&mut debug, n, *data_ptr, memory)?,
+                Op::DefineProcedure(end) => {
+                    procedures.define(memory[*data_ptr], pc);
+                    pc = end;
+                }
+                Op::EndProcedure => pc = procedures.ret(),
+                Op::CallProcedure => pc = procedures.call(memory[*data_ptr], pc)?,
+                Op::End => break,
+                Op::Store => storage = memory[*data_ptr],
+                Op::Load => memory[*data_ptr] = storage,
+                ref op @ (Op::ShiftLeft(_)
+                | Op::ShiftRight(_)
+                | Op::Not
+                | Op::Xor
+                | Op::And
+                | Op::Or) => memory[*data_ptr] = apply_bitwise(op, memory[*data_ptr], storage),
                 _ => unreachable!(),
             }
             pc += 1;
@@ -254,7 +823,8 @@ fn optimize_loop(ops: &[Op], loop_start: usize) -> Option<Op> {
     if ops.len() - loop_start == 2 {
         // [x]
         match ops[loop_start + 1] {
-            Op::IncData(_) | Op::DecData(_) => Some(Op::LoopSetToZero),
+            // Only an odd step is sure to reach zero, whatever the cell holds.
+            Op::IncData(n) | Op::DecData(n) if n % 2 == 1 => Some(Op::LoopSetToZero),
             Op::IncPtr(n) => Some(Op::LoopMovePtr(n as isize)),
             Op::DecPtr(n) => Some(Op::LoopMovePtr(-(n as isize))),
             _ => None,
@@ -280,62 +850,122 @@ fn optimize_loop(ops: &[Op], loop_start: usize) -> Option<Op> {
 
 struct Interpreter3 {
     ops: Vec<Op>,
+    eof: EofPolicy,
+    // The highest cell a run from a fresh tape may reach, if the analysis proves that it moves
+    // the pointer no further.
+    tape_bound: Option<usize>,
+    // `ops` with the loops whose counter the analysis knows folded, run instead of them where the
+    // bounds checks are skipped.
+    folded_ops: Vec<Op>,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl Brainfuck for Interpreter3 {
-    fn new(s: &[u8]) -> Interpreter3 {
-        let instructions = filter_instructions(s);
+    const MEMORY_SIZE: usize = 300000;
+
+    fn with_options(s: &[u8], options: &Options) -> Interpreter3 {
+        let instructions = filter_instructions(s, options);
         let ops = translate_program(&instructions, true);
-        Interpreter3 { ops }
+        let tape_bound = crate::analysis::Analysis::new(&ops).and_then(|a| a.tape_bound(&ops));
+        let folded_ops = match tape_bound {
+            Some(_) => crate::analysis::fold_constant_loops(ops.clone()),
+            None => Vec::new(),
+        };
+        Interpreter3 {
+            ops,
+            eof: options.eof,
+            tape_bound,
+            folded_ops,
+            cancel: options.cancel.clone(),
+        }
     }
-    fn run(&self, read: impl Read, mut write: impl Write) -> Result<()> {
-        let mut memory: Vec<u8> = vec![0; 300000];
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        mut write: impl Write,
+        mut debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
         let mut pc = 0;
-        let mut data_ptr: usize = 0;
-        let mut input = read.bytes();
-        while pc < self.ops.len() {
-            match self.ops[pc] {
-                Op::IncPtr(n) => data_ptr += n,
-                Op::DecPtr(n) => data_ptr -= n,
-                Op::IncData(n) => memory[data_ptr] = memory[data_ptr].wrapping_add(n as u8),
-                Op::DecData(n) => memory[data_ptr] = memory[data_ptr].wrapping_sub(n as u8),
+        let Tape { memory, data_ptr } = tape;
+        let mut input = input_bytes(read);
+        let mut procedures = Procedures::new();
+        let mut storage = 0;
+        // The moves need no bounds checks if the analysis, which assumes a fresh tape, proves that
+        // the program stays on it. Only the cells it may reach have to be fresh.
+        let unchecked = *data_ptr == 0
+            && self
+                .tape_bound
+                .is_some_and(|max| max < memory.len() && memory[..=max].iter().all(|c| *c == 0));
+        // A folded loop may leave the tape elsewhere than the loop would, but such a run never does.
+        let ops = if unchecked {
+            &self.folded_ops
+        } else {
+            &self.ops
+        };
+        while pc < ops.len() {
+            match ops[pc] {
+                Op::IncPtr(n) if unchecked => *data_ptr += n,
+                Op::DecPtr(n) if unchecked => *data_ptr -= n,
+                Op::IncPtr(n) => move_ptr(data_ptr, n as isize, memory.len())?,
+                Op::DecPtr(n) => move_ptr(data_ptr, -(n as isize), memory.len())?,
+                Op::IncData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_add(n as u8),
+                Op::DecData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_sub(n as u8),
                 Op::ReadStdin(n) => {
                     for _ in 0..n {
-                        memory[data_ptr] = input
-                            .next()
-                            .ok_or_else(|| failure::err_msg("input unavailable"))?
-                            .expect("read error");
+                        read_cell(&mut input, &mut memory[*data_ptr], self.eof)?;
                     }
                 }
                 Op::WriteStdout(n) => {
                     for _ in 0..n {
-                        write.write_all(&memory[data_ptr..(data_ptr + 1)])?;
+                        write.write_all(&memory[*data_ptr..(*data_ptr + 1)])?;
                     }
                     write.flush().unwrap();
                 }
                 Op::JumpIfDataIsZero(n) => {
-                    if memory[data_ptr] == 0 {
+                    if memory[*data_ptr] == 0 {
                         pc = n;
                     }
                 }
                 Op::JumpIfDataIsNotZero(n) => {
-                    if memory[data_ptr] != 0 {
+                    if memory[*data_ptr] != 0 {
+                        check_cancel(self.cancel.as_deref())?;
                         pc = n;
                     }
                 }
-                Op::LoopSetToZero => memory[data_ptr] = 0,
-                Op::LoopMovePtr(n) => {
-                    while memory[data_ptr] != 0 {
-                        data_ptr = (data_ptr as isize + n) as usize
-                    }
-                }
+                Op::LoopSetToZero => memory[*data_ptr] = 0,
+                Op::LoopMovePtr(n) => scan_loop(memory, data_ptr, n)?,
                 Op::LoopMoveData(n) => {
-                    if memory[data_ptr] != 0 {
-                        let move_to_ptr = ((data_ptr as isize) + n) as usize;
-                        memory[move_to_ptr] = memory[move_to_ptr].wrapping_add(memory[data_ptr]);
-                        memory[data_ptr] = 0;
+                    if memory[*data_ptr] != 0 {
+                        let from = *data_ptr;
+                        if let Err(e) = move_ptr(data_ptr, n, memory.len()) {
+                            // The unoptimized loop decrements the cell before it moves off the
+                            // tape.
+                            memory[from] = memory[from].wrapping_sub(1);
+                            return Err(e.into());
+                        }
+                        let to = std::mem::replace(data_ptr, from);
+                        memory[to] = memory[to].wrapping_add(memory[from]);
+                        memory[from] = 0;
                     }
                 }
+                Op::DebugDump(n) => dump_state(&mut debug, n, *data_ptr, memory)?,
+                Op::DefineProcedure(end) => {
+                    procedures.define(memory[*data_ptr], pc);
+                    pc = end;
+                }
+                Op::EndProcedure => pc = procedures.ret(),
+                Op::CallProcedure => pc = procedures.call(memory[*data_ptr], pc)?,
+                Op::End => break,
+                Op::Store => storage = memory[*data_ptr],
+                Op::Load => memory[*data_ptr] = storage,
+                ref op @ (Op::ShiftLeft(_)
+                | Op::ShiftRight(_)
+                | Op::Not
+                | Op::Xor
+                | Op::And
+                | Op::Or) => memory[*data_ptr] = apply_bitwise(op, memory[*data_ptr], storage),
+                Op::Fork => unreachable!(),
             }
             pc += 1;
         }
@@ -364,8 +994,7 @@ impl CodeEmitter {
         self.code.extend(bytes);
     }
     fn emit_u32(&mut self, n: u32) {
-        let bytes: [u8; 4] = unsafe { std::mem::transmute(n.to_le()) };
-        self.emit_bytes(&bytes)
+        self.emit_bytes(&n.to_le_bytes())
     }
     fn emit_u64(&mut self, n: u64) {
         self.emit_u32((n & 0xFFFFFFFF) as u32);
@@ -376,7 +1005,7 @@ impl CodeEmitter {
     }
 
     fn replace_u32_at_offset(&mut self, offset: usize, n: u32) {
-        let bytes: [u8; 4] = unsafe { std::mem::transmute(n.to_le()) };
+        let bytes = n.to_le_bytes();
         self.code[offset] = bytes[0];
         self.code[offset + 1] = bytes[1];
         self.code[offset + 2] = bytes[2];
@@ -385,7 +1014,7 @@ impl CodeEmitter {
 }
 
 fn compute_relative_32bit_offset(jump_from: usize, jump_to: usize) -> u32 {
-    if jump_to > jump_from {
+    if jump_to >= jump_from {
         let diff = jump_to - jump_from;
         assert!(diff < (1 << 31));
         diff as u32
@@ -397,28 +1026,158 @@ fn compute_relative_32bit_offset(jump_from: usize, jump_to: usize) -> u32 {
     }
 }
 
-// fn simple_jit(instructions: &[u8], _read_fd: u8, _write_fd: u8) {
-fn simple_jit(instructions: &[u8]) {
+// The generated code reads and writes through these callbacks, so that it can use any `Read` and
+// `Write`. They return non-zero to stop the program, leaving the error in `JitIo::error`.
+struct JitIo<'a> {
+    input: &'a mut dyn Iterator<Item = std::io::Result<u8>>,
+    output: &'a mut dyn Write,
+    eof: EofPolicy,
+    error: Option<failure::Error>,
+}
+
+extern "C" fn jit_read(io: *mut JitIo, data: *mut u8) -> u8 {
+    let io = unsafe { &mut *io };
+    match read_cell(&mut io.input, unsafe { &mut *data }, io.eof) {
+        Ok(()) => 0,
+        Err(e) => {
+            io.error = Some(e);
+            1
+        }
+    }
+}
+
+extern "C" fn jit_write(io: *mut JitIo, data: *const u8) -> u8 {
+    let io = unsafe { &mut *io };
+    let byte = unsafe { *data };
+    match io.output.write_all(&[byte]).and_then(|_| io.output.flush()) {
+        Ok(()) => 0,
+        Err(e) => {
+            io.error = Some(e.into());
+            1
+        }
+    }
+}
+
+// Returns the offset of the cell a scan loop of `stride` stops at, from `memory`, or `usize::MAX` if
+// it moves off the tape.
+extern "C" fn jit_scan(memory: *const u8, len: usize, data: *const u8, stride: isize) -> usize {
+    let memory = unsafe { std::slice::from_raw_parts(memory, len) };
+    let from = data as usize - memory.as_ptr() as usize;
+    scan::find_zero(memory, from, stride).unwrap_or(usize::MAX)
+}
+
+// Whether `s` starts with a scan loop, like `[>]` or `[<<]`.
+fn is_scan_loop(s: &[u8]) -> bool {
+    match s.iter().skip(1).position(|c| *c != s[1]) {
+        Some(n) => n > 0 && (s[1] == b'>' || s[1] == b'<') && s[n + 1] == b']',
+        None => false,
+    }
+}
+
+extern "C" fn jit_out_of_bounds(io: *mut JitIo) -> u8 {
+    let io = unsafe { &mut *io };
+    io.error = Some(RuntimeError::PointerOutOfBounds.into());
+    1
+}
+
+extern "C" fn jit_cancelled(io: *mut JitIo) -> u8 {
+    let io = unsafe { &mut *io };
+    io.error = Some(RuntimeError::Cancelled.into());
+    1
+}
+
+// Emits a call to `callback(%r12, %r13)`, and a jump to the exit of the program if it returns
+// non-zero. Returns the offset of the jump, to be fixed up once the exit is known.
+fn emit_io_call(emitter: &mut CodeEmitter, callback: usize) -> usize {
+    // mov %r12, %rdi
+    emitter.emit_bytes(&[0x4C, 0x89, 0xE7]);
+    // mov %r13, %rsi
+    emitter.emit_bytes(&[0x4C, 0x89, 0xEE]);
+    // movabs <callback>, %rax
+    emitter.emit_bytes(&[0x48, 0xB8]);
+    emitter.emit_u64(callback as u64);
+    // call *%rax
+    emitter.emit_bytes(&[0xFF, 0xD0]);
+    // test %al, %al
+    emitter.emit_bytes(&[0x84, 0xC0]);
+    // jnz <exit>
+    let jump = emitter.size();
+    emitter.emit_bytes(&[0x0F, 0x85]);
+    emitter.emit_u32(0);
+    jump
+}
+
+// With `cancel`, each loop checks it when it repeats.
+fn simple_jit(instructions: &[u8], cancel: Option<&AtomicBool>) -> mmap::MemoryMap {
     // https://www.systutorials.com/240986/x86-64-calling-convention-by-gcc/
     // The calling convention of the System V AMD64 ABI is followed on GNU/Linux.
     // The registers RDI, RSI, RDX, RCX, R8, and R9 are used for integer
     // and memory address arguments and XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6 and XMM7
     // are used for floating point arguments.
+    //
+    // The generated function is
+    // `extern "C" fn(io: *mut JitIo, data: *mut u8, memory: *mut u8, len: usize) -> *mut u8`. It
+    // keeps `io` in %r12, the data pointer in %r13, `memory` in %r14 and `len` in %r15, all
+    // callee-saved, and returns the final data pointer.
 
-    let memory: Vec<u8> = vec![0; 30000];
     let mut open_bracket_stack = Vec::<usize>::new();
+    let mut exit_jumps = Vec::<usize>::new();
+    let mut out_of_bounds_right_jumps = Vec::<usize>::new();
+    let mut out_of_bounds_left_jumps = Vec::<usize>::new();
+    let mut cancelled_jumps = Vec::<usize>::new();
     let mut emitter = CodeEmitter::new();
 
-    // movabs <address of memory.data>, %r13
-    emitter.emit_bytes(&[0x49, 0xBD]);
-    emitter.emit_u64(memory.as_ptr() as u64);
+    // push %r12
+    // push %r13
+    // push %r14
+    // push %r15
+    // sub $8, %rsp (to keep the stack 16-byte aligned for calls)
+    emitter.emit_bytes(&[0x41, 0x54]);
+    emitter.emit_bytes(&[0x41, 0x55]);
+    emitter.emit_bytes(&[0x41, 0x56]);
+    emitter.emit_bytes(&[0x41, 0x57]);
+    emitter.emit_bytes(&[0x48, 0x83, 0xEC, 0x08]);
+    // mov %rdi, %r12
+    emitter.emit_bytes(&[0x49, 0x89, 0xFC]);
+    // mov %rsi, %r13
+    emitter.emit_bytes(&[0x49, 0x89, 0xF5]);
+    // mov %rdx, %r14
+    emitter.emit_bytes(&[0x49, 0x89, 0xD6]);
+    // mov %rcx, %r15
+    emitter.emit_bytes(&[0x49, 0x89, 0xCF]);
 
-    for inst in instructions {
+    let mut pc = 0;
+    while pc < instructions.len() {
+        let inst = instructions[pc];
+        pc += 1;
         match inst {
-            // Inc %r13
-            b'>' => emitter.emit_bytes(&[0x49, 0xFF, 0xC5]),
-            // Dec %r13
-            b'<' => emitter.emit_bytes(&[0x49, 0xFF, 0xCD]),
+            b'>' | b'<' => {
+                // A run of the same move is checked once, like `Op::IncPtr` and `Op::DecPtr`.
+                let start = pc - 1;
+                while pc < instructions.len() && instructions[pc] == inst {
+                    pc += 1;
+                }
+                let n = (pc - start) as u32;
+                let offset = if inst == b'>' { n } else { n.wrapping_neg() };
+                // lea offset(%r13), %rax
+                emitter.emit_bytes(&[0x49, 0x8D, 0x85]);
+                emitter.emit_u32(offset);
+                // sub %r14, %rax
+                emitter.emit_bytes(&[0x4C, 0x29, 0xF0]);
+                // cmp %r15, %rax
+                emitter.emit_bytes(&[0x4C, 0x39, 0xF8]);
+                // jae <out_of_bounds> (unsigned, so that it also catches moves below `memory`)
+                if inst == b'>' {
+                    out_of_bounds_right_jumps.push(emitter.size());
+                } else {
+                    out_of_bounds_left_jumps.push(emitter.size());
+                }
+                emitter.emit_bytes(&[0x0F, 0x83]);
+                emitter.emit_u32(0);
+                // lea offset(%r13), %r13
+                emitter.emit_bytes(&[0x4D, 0x8D, 0xAD]);
+                emitter.emit_u32(offset);
+            }
             b'+' => {
                 // addb $1, 0(%r13)
                 emitter.emit_bytes(&[0x41, 0x80, 0x45, 0x00, 0x01]);
@@ -427,30 +1186,44 @@ fn simple_jit(instructions: &[u8]) {
                 // subb $1, 0(%r13)
                 emitter.emit_bytes(&[0x41, 0x80, 0x6D, 0x00, 0x01]);
             }
-            b'.' => {
-                // To emit one byte to stdout, call the write syscall with fd=1 (for
-                // stdout), buf=address of byte, count=1.
-                //
-                // mov $1, %rax
-                // mov $1, %rdi
-                // mov %r13, %rsi
-                // mov $1, %rdx
-                // syscall
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC0, 0x01, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC7, 0x01, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x4C, 0x89, 0xEE]);
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC2, 0x01, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x0F, 0x05]);
-            }
-            b',' => {
-                // To read one byte from stdin, call the read syscall with fd=0 (for
-                // stdin),
-                // buf=address of byte, count=1.
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC0, 0x00, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC7, 0x00, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x4C, 0x89, 0xEE]);
-                emitter.emit_bytes(&[0x48, 0xC7, 0xC2, 0x01, 0x00, 0x00, 0x00]);
-                emitter.emit_bytes(&[0x0F, 0x05]);
+            b'.' => exit_jumps.push(emit_io_call(&mut emitter, jit_write as *const () as usize)),
+            b',' => exit_jumps.push(emit_io_call(&mut emitter, jit_read as *const () as usize)),
+            b'[' if is_scan_loop(&instructions[pc - 1..]) => {
+                let inst = instructions[pc];
+                let start = pc;
+                while instructions[pc] == inst {
+                    pc += 1;
+                }
+                // Skip the `]`.
+                pc += 1;
+                let n = (pc - 1 - start) as u32;
+                let stride = if inst == b'>' { n } else { n.wrapping_neg() };
+                // mov %r14, %rdi
+                emitter.emit_bytes(&[0x4C, 0x89, 0xF7]);
+                // mov %r15, %rsi
+                emitter.emit_bytes(&[0x4C, 0x89, 0xFE]);
+                // mov %r13, %rdx
+                emitter.emit_bytes(&[0x4C, 0x89, 0xEA]);
+                // mov $stride, %rcx (sign-extended)
+                emitter.emit_bytes(&[0x48, 0xC7, 0xC1]);
+                emitter.emit_u32(stride);
+                // movabs <jit_scan>, %rax
+                emitter.emit_bytes(&[0x48, 0xB8]);
+                emitter.emit_u64(jit_scan as *const () as usize as u64);
+                // call *%rax
+                emitter.emit_bytes(&[0xFF, 0xD0]);
+                // cmp $-1, %rax
+                emitter.emit_bytes(&[0x48, 0x83, 0xF8, 0xFF]);
+                // je <out_of_bounds>
+                if inst == b'>' {
+                    out_of_bounds_right_jumps.push(emitter.size());
+                } else {
+                    out_of_bounds_left_jumps.push(emitter.size());
+                }
+                emitter.emit_bytes(&[0x0F, 0x84]);
+                emitter.emit_u32(0);
+                // lea (%r14,%rax), %r13
+                emitter.emit_bytes(&[0x4D, 0x8D, 0x2C, 0x06]);
             }
             b'[' => {
                 // For the jumps we always emit the instruciton for 32-bit pc-relative
@@ -468,6 +1241,17 @@ fn simple_jit(instructions: &[u8]) {
             b']' => {
                 assert!(!open_bracket_stack.is_empty());
                 let open_bracket_offset = open_bracket_stack.pop().unwrap();
+                if let Some(cancel) = cancel {
+                    // movabs <cancel>, %rax
+                    emitter.emit_bytes(&[0x48, 0xB8]);
+                    emitter.emit_u64(cancel as *const AtomicBool as u64);
+                    // cmpb $0, (%rax)
+                    emitter.emit_bytes(&[0x80, 0x38, 0x00]);
+                    // jnz <cancelled>
+                    cancelled_jumps.push(emitter.size());
+                    emitter.emit_bytes(&[0x0F, 0x85]);
+                    emitter.emit_u32(0);
+                }
                 // cmpb $0, 0(%r13)
                 emitter.emit_bytes(&[0x41, 0x80, 0x7d, 0x00, 0x00]);
 
@@ -490,9 +1274,80 @@ fn simple_jit(instructions: &[u8]) {
         }
     }
 
+    let exit = emitter.size();
+    for jump in exit_jumps {
+        let pcrel_offset = compute_relative_32bit_offset(jump + 6, exit);
+        emitter.replace_u32_at_offset(jump + 2, pcrel_offset);
+    }
+    // mov %r13, %rax
+    emitter.emit_bytes(&[0x4C, 0x89, 0xE8]);
+    // add $8, %rsp
+    // pop %r15
+    // pop %r14
+    // pop %r13
+    // pop %r12
+    emitter.emit_bytes(&[0x48, 0x83, 0xC4, 0x08]);
+    emitter.emit_bytes(&[0x41, 0x5F]);
+    emitter.emit_bytes(&[0x41, 0x5E]);
+    emitter.emit_bytes(&[0x41, 0x5D]);
+    emitter.emit_bytes(&[0x41, 0x5C]);
+
     // Emit a 'ret'
     emitter.emit_byte(0xC3);
 
+    // Like `move_ptr`, a pointer moving off the tape stops at its end.
+    let out_of_bounds_right = emitter.size();
+    for jump in out_of_bounds_right_jumps {
+        let pcrel_offset = compute_relative_32bit_offset(jump + 6, out_of_bounds_right);
+        emitter.replace_u32_at_offset(jump + 2, pcrel_offset);
+    }
+    // lea -1(%r14,%r15), %r13
+    emitter.emit_bytes(&[0x4F, 0x8D, 0x6C, 0x3E, 0xFF]);
+    // jmp <out_of_bounds>
+    let jump_to_out_of_bounds = emitter.size();
+    emitter.emit_byte(0xE9);
+    emitter.emit_u32(0);
+
+    let out_of_bounds_left = emitter.size();
+    for jump in out_of_bounds_left_jumps {
+        let pcrel_offset = compute_relative_32bit_offset(jump + 6, out_of_bounds_left);
+        emitter.replace_u32_at_offset(jump + 2, pcrel_offset);
+    }
+    // mov %r14, %r13
+    emitter.emit_bytes(&[0x4D, 0x89, 0xF5]);
+
+    let out_of_bounds = emitter.size();
+    let pcrel_offset = compute_relative_32bit_offset(jump_to_out_of_bounds + 5, out_of_bounds);
+    emitter.replace_u32_at_offset(jump_to_out_of_bounds + 1, pcrel_offset);
+    // mov %r12, %rdi
+    emitter.emit_bytes(&[0x4C, 0x89, 0xE7]);
+    // movabs <jit_out_of_bounds>, %rax
+    emitter.emit_bytes(&[0x48, 0xB8]);
+    emitter.emit_u64(jit_out_of_bounds as *const () as usize as u64);
+    // call *%rax
+    emitter.emit_bytes(&[0xFF, 0xD0]);
+    // jmp <exit>
+    let pcrel_offset = compute_relative_32bit_offset(emitter.size() + 5, exit);
+    emitter.emit_byte(0xE9);
+    emitter.emit_u32(pcrel_offset);
+
+    let cancelled = emitter.size();
+    for jump in cancelled_jumps {
+        let pcrel_offset = compute_relative_32bit_offset(jump + 6, cancelled);
+        emitter.replace_u32_at_offset(jump + 2, pcrel_offset);
+    }
+    // mov %r12, %rdi
+    emitter.emit_bytes(&[0x4C, 0x89, 0xE7]);
+    // movabs <jit_cancelled>, %rax
+    emitter.emit_bytes(&[0x48, 0xB8]);
+    emitter.emit_u64(jit_cancelled as *const () as usize as u64);
+    // call *%rax
+    emitter.emit_bytes(&[0xFF, 0xD0]);
+    // jmp <exit>
+    let pcrel_offset = compute_relative_32bit_offset(emitter.size() + 5, exit);
+    emitter.emit_byte(0xE9);
+    emitter.emit_u32(pcrel_offset);
+
     // JitProgram
     let rwx = &[
         mmap::MapOption::MapReadable,
@@ -505,23 +1360,66 @@ fn simple_jit(instructions: &[u8]) {
         std::ptr::copy(emitter.code.as_ptr(), mapping.data(), emitter.size());
     }
     debug!("jit: size: {}", emitter.size());
-    let func: fn() = unsafe { std::mem::transmute(mapping.data()) };
-    func();
+    mapping
+}
+
+fn run_jit(
+    mapping: &mmap::MemoryMap,
+    read: impl Read,
+    mut write: impl Write,
+    tape: &mut Tape,
+    eof: EofPolicy,
+) -> Result<()> {
+    let func: extern "C" fn(*mut JitIo, *mut u8, *mut u8, usize) -> *mut u8 =
+        unsafe { std::mem::transmute(mapping.data()) };
+
+    let mut input = input_bytes(read);
+    let mut io = JitIo {
+        input: &mut input,
+        output: &mut write,
+        eof,
+        error: None,
+    };
+    let len = tape.memory.len();
+    let memory = tape.memory.as_mut_ptr();
+    let data = func(&mut io, unsafe { memory.add(tape.data_ptr) }, memory, len);
+    tape.data_ptr = data as usize - memory as usize;
+    match io.error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
 struct Jit1 {
-    instructions: Vec<u8>,
+    mapping: mmap::MemoryMap,
+    eof: EofPolicy,
+    // Kept alive for the generated code, which reads it.
+    _cancel: Option<Arc<AtomicBool>>,
 }
 
 impl Brainfuck for Jit1 {
-    fn new(s: &[u8]) -> Jit1 {
-        let instructions = filter_instructions(s);
-        Jit1 { instructions }
+    fn with_options(s: &[u8], options: &Options) -> Jit1 {
+        // The generated code has no way to reach the debug sink, so `Engine::compile` rejects `#`,
+        // and it is dropped here.
+        let options = Options {
+            debug_hash: false,
+            ..options.clone()
+        };
+        let instructions = filter_instructions(s, &options);
+        Jit1 {
+            mapping: simple_jit(&instructions, options.cancel.as_deref()),
+            eof: options.eof,
+            _cancel: options.cancel,
+        }
     }
-    fn run(&self, _: impl Read, _: impl Write) -> Result<()> {
-        // TODO: Use read and write as stdin and stdout
-        simple_jit(&self.instructions);
-        Ok(())
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        _: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        run_jit(&self.mapping, read, write, tape, self.eof)
     }
 }
 
@@ -536,18 +1434,223 @@ pub fn run<R: Read, W: Write>(
     optimize: Option<u64>,
     jit: bool,
 ) -> Result<()> {
-    if jit {
-        Jit1::new(s).run(r, w)
+    run_with_options(
+        s,
+        r,
+        w,
+        std::io::stderr(),
+        optimize,
+        jit,
+        &Options::default(),
+    )
+}
+
+pub fn run_with_options<R: Read, W: Write, D: Write>(
+    s: &[u8],
+    r: R,
+    w: W,
+    d: D,
+    optimize: Option<u64>,
+    jit: bool,
+    options: &Options,
+) -> Result<()> {
+    let engine = if jit {
+        Engine::Jit1
     } else if let Some(o) = optimize {
         match o {
-            1 => Interpreter1::new(s).run(r, w),
-            2 => Interpreter2::new(s).run(r, w),
-            3 => Interpreter3::new(s).run(r, w),
-            _ => unimplemented!(),
+            1 => Engine::Interpreter1,
+            2 => Engine::Interpreter2,
+            3 => Engine::Interpreter3,
+            4 => Engine::Interpreter4,
+            _ => return Err(failure::format_err!("unknown optimization level {}", o)),
         }
     } else {
         // TODO: Fix the default
-        run_default(s, r, w)
+        Engine::Interpreter1
+    };
+    engine.run_on_tape(s, options, r, w, d, &mut Tape::new(engine.memory_size()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Interpreter1,
+    Interpreter2,
+    Interpreter3,
+    Interpreter4,
+    Jit1,
+}
+
+impl Engine {
+    // Every engine which can run on this platform.
+    pub fn all() -> Vec<Engine> {
+        let mut engines = vec![
+            Engine::Interpreter1,
+            Engine::Interpreter2,
+            Engine::Interpreter3,
+            Engine::Interpreter4,
+        ];
+        if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            engines.push(Engine::Jit1);
+        }
+        engines
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Interpreter1 => "interpreter1",
+            Engine::Interpreter2 => "interpreter2",
+            Engine::Interpreter3 => "interpreter3",
+            Engine::Interpreter4 => "interpreter4",
+            Engine::Jit1 => "jit1",
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Engine::Interpreter1 => Interpreter1::MEMORY_SIZE,
+            Engine::Interpreter2 => Interpreter2::MEMORY_SIZE,
+            Engine::Interpreter3 => Interpreter3::MEMORY_SIZE,
+            Engine::Interpreter4 => Interpreter4::MEMORY_SIZE,
+            Engine::Jit1 => Jit1::MEMORY_SIZE,
+        }
+    }
+
+    pub fn compile(self, s: &[u8], options: &Options) -> Result<CompiledProgram> {
+        check_program_brackets(s, options)?;
+        let instructions = filter_instructions(s, options);
+        if instructions.contains(&b'Y') {
+            return Err(failure::format_err!(
+                "{} does not support brainfork's Y",
+                self
+            ));
+        }
+        if self == Engine::Jit1 && instructions.contains(&b'#') {
+            return Err(failure::format_err!("{} does not support `#`", self));
+        }
+        if self == Engine::Jit1 && !is_plain_brainfuck(&instructions) {
+            return Err(failure::format_err!(
+                "{} does not support the extensions of {}",
+                self,
+                options.dialect
+            ));
+        }
+        // The pc of `#` is that of the whole program, so a program with one is never split.
+        let residual = if options.partial_eval && is_plain_brainfuck(&instructions) {
+            let prefix = crate::prefix::evaluate(
+                &instructions,
+                self.memory_size(),
+                crate::prefix::MAX_STEPS,
+            );
+            let options = Options {
+                dialect: Dialect::brainfuck(),
+                ..options.clone()
+            };
+            let rest = self.compile_unchecked(&prefix.rest, &options);
+            Some((prefix, rest))
+        } else {
+            None
+        };
+        Ok(CompiledProgram {
+            compiled: self.compile_unchecked(s, options),
+            residual,
+        })
+    }
+
+    fn compile_unchecked(self, s: &[u8], options: &Options) -> Compiled {
+        match self {
+            Engine::Interpreter1 => Compiled::Interpreter1(Interpreter1::with_options(s, options)),
+            Engine::Interpreter2 => Compiled::Interpreter2(Interpreter2::with_options(s, options)),
+            Engine::Interpreter3 => Compiled::Interpreter3(Interpreter3::with_options(s, options)),
+            Engine::Interpreter4 => Compiled::Interpreter4(Interpreter4::with_options(s, options)),
+            Engine::Jit1 => Compiled::Jit1(Jit1::with_options(s, options)),
+        }
+    }
+
+    pub fn run_on_tape<R: Read, W: Write, D: Write>(
+        self,
+        s: &[u8],
+        options: &Options,
+        r: R,
+        w: W,
+        d: D,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        self.compile(s, options)?.run_on_tape(r, w, d, tape)
+    }
+}
+
+// A program prepared by an engine, which can be run many times.
+pub struct CompiledProgram {
+    compiled: Compiled,
+    // With `Options::partial_eval`, the prefix of the program, and the rest of it, which a run on
+    // a fresh tape starts with instead.
+    residual: Option<(crate::prefix::Prefix, Compiled)>,
+}
+
+enum Compiled {
+    Interpreter1(Interpreter1),
+    Interpreter2(Interpreter2),
+    Interpreter3(Interpreter3),
+    Interpreter4(Interpreter4),
+    Jit1(Jit1),
+}
+
+impl CompiledProgram {
+    pub fn run_on_tape<R: Read, W: Write, D: Write>(
+        &self,
+        r: R,
+        mut w: W,
+        d: D,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        if let Some((prefix, rest)) = &self.residual {
+            if tape.data_ptr == 0
+                && prefix.memory.len() <= tape.memory.len()
+                && tape.memory.iter().all(|c| *c == 0)
+            {
+                w.write_all(&prefix.output)?;
+                w.flush()?;
+                tape.memory[..prefix.memory.len()].copy_from_slice(&prefix.memory);
+                tape.data_ptr = prefix.data_ptr;
+                return rest.run_on_tape(r, w, d, tape);
+            }
+        }
+        self.compiled.run_on_tape(r, w, d, tape)
+    }
+}
+
+impl Compiled {
+    fn run_on_tape<R: Read, W: Write, D: Write>(
+        &self,
+        r: R,
+        w: W,
+        d: D,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        match self {
+            Compiled::Interpreter1(p) => p.run_on_tape(r, w, d, tape),
+            Compiled::Interpreter2(p) => p.run_on_tape(r, w, d, tape),
+            Compiled::Interpreter3(p) => p.run_on_tape(r, w, d, tape),
+            Compiled::Interpreter4(p) => p.run_on_tape(r, w, d, tape),
+            Compiled::Jit1(p) => p.run_on_tape(r, w, d, tape),
+        }
+    }
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Engine {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Engine> {
+        Engine::all()
+            .into_iter()
+            .find(|engine| engine.name() == s)
+            .ok_or_else(|| failure::format_err!("unknown engine: {}", s))
     }
 }
 
@@ -555,7 +1658,6 @@ pub fn run<R: Read, W: Write>(
 mod tests {
 
     use super::*;
-    use serde_derive::*;
     use std::path::{Path, PathBuf};
 
     #[test]
@@ -574,21 +1676,232 @@ mod tests {
         assert_eq!(out, b"12345");
     }
 
+    #[test]
+    fn optimize_level_test() {
+        for o in 1..=4 {
+            let mut out = Vec::new();
+            run(
+                b"++++++[>++++++++<-]>+.",
+                &[] as &[u8],
+                &mut out,
+                Some(o),
+                false,
+            )
+            .unwrap();
+            assert_eq!(out, b"1");
+        }
+        let mut out = Vec::new();
+        let result = run(b"+", &[] as &[u8], &mut out, Some(5), false);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "unknown optimization level 5"
+        );
+    }
+
+    #[test]
+    fn debug_hash_test() {
+        let s = b"++>+++#[-]<.#";
+        let options = Options {
+            debug_hash: true,
+            ..Options::default()
+        };
+        let mut expected_debug = Vec::new();
+        expected_debug.extend_from_slice(b"#: pc=6 ptr=1 cells[0..10]: 2 [3] 0 0 0 0 0 0 0 0\n");
+        expected_debug.extend_from_slice(b"#: pc=12 ptr=0 cells[0..9]: [2] 0 0 0 0 0 0 0 0\n");
+
+        fn assert_debug<P: Brainfuck>(s: &[u8], options: &Options, expected_debug: &[u8]) {
+            let mut out = Vec::new();
+            let mut debug = Vec::new();
+            P::with_options(s, options)
+                .run_with_debug(&[] as &[u8], &mut out, &mut debug)
+                .unwrap();
+            assert_eq!(out, b"\x02");
+            assert_eq!(debug, expected_debug);
+        }
+        assert_debug::<Interpreter1>(s, &options, &expected_debug);
+        assert_debug::<Interpreter2>(s, &options, &expected_debug);
+        assert_debug::<Interpreter3>(s, &options, &expected_debug);
+        assert_debug::<Interpreter4>(s, &options, &expected_debug);
+
+        // Without the option, `#` is a comment.
+        assert_debug::<Interpreter3>(s, &Options::default(), b"");
+
+        // The JIT cannot dump the machine state.
+        assert_eq!(
+            Engine::Jit1.compile(s, &options).err().unwrap().to_string(),
+            "jit1 does not support `#`"
+        );
+        assert!(Engine::Jit1.compile(s, &Options::default()).is_ok());
+    }
+
+    #[test]
+    fn check_brackets_test() {
+        assert_eq!(check_brackets(b"+[[-]>[<]]"), Ok(()));
+        assert_eq!(
+            check_brackets(b"+[[-]"),
+            Err(SyntaxError::UnmatchedOpenBracket(1))
+        );
+        assert_eq!(
+            check_brackets(b"[]]["),
+            Err(SyntaxError::UnmatchedCloseBracket(2))
+        );
+
+        let mut out = Vec::new();
+        let result = run(b"+]", &[] as &[u8], &mut out, Some(3), false);
+        assert!(result.is_err());
+
+        // Offsets are in the source, whatever its dialect.
+        let options = Options {
+            dialect: Dialect::ook(),
+            ..Options::default()
+        };
+        let e = Engine::Interpreter3
+            .compile(b"Ook. Ook.\nOok! Ook?", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.downcast_ref::<SyntaxError>(),
+            Some(&SyntaxError::UnmatchedOpenBracket(10))
+        );
+    }
+
+    #[test]
+    fn cancel_test() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = Options {
+            cancel: Some(cancel.clone()),
+            ..Options::default()
+        };
+        let run = |engine: Engine, s: &[u8]| {
+            engine
+                .run_on_tape(
+                    s,
+                    &options,
+                    &[] as &[u8],
+                    std::io::sink(),
+                    std::io::sink(),
+                    &mut Tape::new(4),
+                )
+                .err()
+                .map(|e| error_kind(&e))
+        };
+        for engine in Engine::all() {
+            assert_eq!(run(engine, b"++[>+<-]"), None);
+        }
+        // Each kind of loop stops, including a loop of a block in interpreter4.
+        cancel.store(true, Ordering::Relaxed);
+        for engine in Engine::all() {
+            for s in &[&b"+[]"[..], b"+[>+<]", b"+[[-]+]"] {
+                assert_eq!(run(engine, s), Some("cancelled".to_string()), "{}", engine);
+            }
+        }
+    }
+
+    #[test]
+    fn extended_type_1_test() {
+        let options = Options {
+            dialect: Dialect::extended_type_1(),
+            ..Options::default()
+        };
+        let s = b"+++++$>!{{^.~.}}}}}}}}}.+++&.~~|.@.";
+        for engine in &[
+            Engine::Interpreter1,
+            Engine::Interpreter2,
+            Engine::Interpreter3,
+            Engine::Interpreter4,
+        ] {
+            let mut out = Vec::new();
+            engine
+                .run_on_tape(
+                    s,
+                    &options,
+                    &[] as &[u8],
+                    &mut out,
+                    std::io::sink(),
+                    &mut Tape::new(4),
+                )
+                .unwrap();
+            assert_eq!(out, [17, 238, 0, 1, 5]);
+        }
+        assert!(Engine::Jit1.compile(s, &options).is_err());
+
+        assert!(matches!(
+            translate_program(b"~~^^^{{{&&$$", true)[..],
+            [Op::Xor, Op::ShiftLeft(3), Op::And, Op::Store]
+        ));
+        assert!(matches!(
+            translate_program(b"[~~-]", true)[..],
+            [Op::LoopSetToZero]
+        ));
+        assert_eq!(translate_program(b"[~-]", true).len(), 4);
+    }
+
+    #[test]
+    fn pbrain_test() {
+        let options = Options {
+            dialect: Dialect::pbrain(),
+            ..Options::default()
+        };
+        let run = |engine: Engine, s: &[u8]| {
+            let mut out = Vec::new();
+            let mut tape = Tape::new(4);
+            engine
+                .run_on_tape(
+                    s,
+                    &options,
+                    &[] as &[u8],
+                    &mut out,
+                    std::io::sink(),
+                    &mut tape,
+                )
+                .map(|()| out)
+                .map_err(|e| error_kind(&e))
+        };
+        for engine in &[
+            Engine::Interpreter1,
+            Engine::Interpreter2,
+            Engine::Interpreter3,
+            Engine::Interpreter4,
+        ] {
+            // Procedure 1 adds 3 to the next cell.
+            assert_eq!(run(*engine, b"+(>+++<):::>."), Ok(vec![9]));
+            // Procedures can call each other, and be redefined.
+            assert_eq!(run(*engine, b"+(>+<)+(-:+):+(>-<):>."), Ok(vec![0]));
+            assert_eq!(run(*engine, b":"), Err("undefined-procedure".to_string()));
+            assert_eq!(
+                run(*engine, b"+(:):"),
+                Err("call-stack-overflow".to_string())
+            );
+        }
+        assert!(Engine::Jit1.compile(b"+(>+<):", &options).is_err());
+        assert!(Engine::Jit1.compile(b"+>+<", &options).is_ok());
+
+        assert_eq!(check_brackets(b"+(>[-]<):"), Ok(()));
+        assert_eq!(
+            check_brackets(b"([)]"),
+            Err(SyntaxError::UnmatchedOpenBracket(1))
+        );
+        assert_eq!(
+            check_brackets(b"[(])"),
+            Err(SyntaxError::UnmatchedCloseBracket(2))
+        );
+        assert_eq!(check_brackets(b"(()"), Err(SyntaxError::NestedProcedure(1)));
+        assert_eq!(
+            check_brackets(b"+("),
+            Err(SyntaxError::UnmatchedOpenParen(1))
+        );
+        assert_eq!(
+            check_brackets(b"+)"),
+            Err(SyntaxError::UnmatchedCloseParen(1))
+        );
+    }
+
     fn path_from_project_root(path: impl AsRef<Path>) -> PathBuf {
         let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         root.push(path.as_ref());
         root
     }
 
-    // Use code-for-blog/2017/bfjit/tests/testcases
-    #[derive(Debug, PartialEq, Serialize, Deserialize)]
-    struct Expected {
-        #[serde(rename = "feed-in")]
-        feed_in: String,
-        #[serde(rename = "expect-out")]
-        expect_out: String,
-    }
-
     fn assert_program_output<P: Brainfuck>(s: &[u8], stdin: &[u8], expected_output: &[u8]) {
         let mut out = Vec::new();
         P::new(s).run(stdin, &mut out).unwrap();
@@ -598,30 +1911,26 @@ mod tests {
     #[test]
     fn assert_output() {
         let test_dir = path_from_project_root("src/testcases");
-        let num_brainfuck_program = glob::glob(&format!("{}/{}", test_dir.display(), "*.bf"))
-            .unwrap()
-            .map(|bf| {
-                let bf = bf.unwrap();
-                eprintln!("testing: {}", bf.display());
-                let test = PathBuf::from(&bf).with_extension("test");
-
-                let bf = std::fs::read_to_string(bf).unwrap();
-                let bf = bf.as_bytes();
-                let test = std::fs::read_to_string(test).unwrap();
-                let expected: Expected = serde_json::from_str(&test).unwrap();
-
-                let stdin = expected.feed_in.as_bytes();
-                let expected_output = expected.expect_out.as_bytes();
-
-                assert_program_output::<Interpreter1>(bf, stdin, expected_output);
-                assert_program_output::<Interpreter2>(bf, stdin, expected_output);
-                assert_program_output::<Interpreter3>(bf, stdin, expected_output);
-            })
-            .count();
+        let tests = crate::testcase::load_dir(test_dir).unwrap();
+        for test in &tests {
+            let test = test.as_ref().unwrap();
+            // Failures are checked by `testcase::run_test`.
+            if test.expected.expect_error.is_some() {
+                continue;
+            }
+            eprintln!("testing: {}", test.path.display());
+            let bf = &test.program;
+            let stdin = test.expected.feed_in.as_bytes();
+            let expected_output = test.expected.expect_out.as_bytes();
+
+            assert_program_output::<Interpreter1>(bf, stdin, expected_output);
+            assert_program_output::<Interpreter2>(bf, stdin, expected_output);
+            assert_program_output::<Interpreter3>(bf, stdin, expected_output);
+            assert_program_output::<Interpreter4>(bf, stdin, expected_output);
+        }
         assert!(
-            num_brainfuck_program > 5,
+            tests.len() > 5,
             "the number of test cases should be more than 5?"
         );
     }
-
 }
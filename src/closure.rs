@@ -0,0 +1,403 @@
+// Closure compilation: a portable tier which compiles each `Op` of a program into a closure that
+// runs it and returns the pc of the next op. The targets of jumps are resolved and the operands
+// captured when the program is compiled, so running a program only calls one closure after
+// another, without matching on the op.
+//
+// A run of ops which only move the pointer and change cells is compiled into one closure, which
+// applies them at their offsets from the pointer. A loop whose body is such a run is one closure
+// too, which runs the whole loop.
+use crate::brainfuck::*;
+use crate::scan::scan_loop;
+use std::io::Read;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// The pc a closure returns to stop the program, with `Machine::error` set if it failed.
+const STOP: usize = usize::MAX;
+
+// The state the closures run on.
+struct Machine<'a> {
+    memory: &'a mut [u8],
+    data_ptr: usize,
+    input: &'a mut dyn Iterator<Item = std::io::Result<u8>>,
+    output: &'a mut dyn Write,
+    debug: &'a mut dyn Write,
+    eof: EofPolicy,
+    procedures: Procedures,
+    storage: u8,
+    cancel: Option<&'a AtomicBool>,
+    error: Option<failure::Error>,
+}
+
+impl Machine<'_> {
+    fn cell(&mut self) -> &mut u8 {
+        &mut self.memory[self.data_ptr]
+    }
+
+    // Stops the program with `e`.
+    fn fail(&mut self, e: impl Into<failure::Error>) -> usize {
+        self.error = Some(e.into());
+        STOP
+    }
+
+    // Goes to `pc` to repeat a loop, unless the run has been cancelled.
+    fn repeat(&mut self, pc: usize) -> usize {
+        match check_cancel(self.cancel) {
+            Ok(()) => pc,
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn move_ptr(&mut self, offset: isize, next: usize) -> usize {
+        match move_ptr(&mut self.data_ptr, offset, self.memory.len()) {
+            Ok(()) => next,
+            Err(e) => self.fail(e),
+        }
+    }
+}
+
+type Closure = Box<dyn Fn(&mut Machine<'_>) -> usize>;
+
+fn closure(f: impl Fn(&mut Machine<'_>) -> usize + 'static) -> Closure {
+    Box::new(f)
+}
+
+// The closure of `op`, which is at `pc`.
+fn compile(op: Op, pc: usize) -> Closure {
+    let next = pc + 1;
+    match op {
+        Op::IncPtr(n) => closure(move |m| m.move_ptr(n as isize, next)),
+        Op::DecPtr(n) => closure(move |m| m.move_ptr(-(n as isize), next)),
+        Op::IncData(n) => closure(move |m| {
+            let cell = m.cell();
+            *cell = cell.wrapping_add(n as u8);
+            next
+        }),
+        Op::DecData(n) => closure(move |m| {
+            let cell = m.cell();
+            *cell = cell.wrapping_sub(n as u8);
+            next
+        }),
+        Op::ReadStdin(n) => closure(move |m| {
+            for _ in 0..n {
+                if let Err(e) = read_cell(&mut m.input, &mut m.memory[m.data_ptr], m.eof) {
+                    return m.fail(e);
+                }
+            }
+            next
+        }),
+        Op::WriteStdout(n) => closure(move |m| {
+            let byte = *m.cell();
+            for _ in 0..n {
+                if let Err(e) = m.output.write_all(&[byte]) {
+                    return m.fail(e);
+                }
+            }
+            match m.output.flush() {
+                Ok(()) => next,
+                Err(e) => m.fail(e),
+            }
+        }),
+        // A jump goes past the bracket it jumps to.
+        Op::JumpIfDataIsZero(end) => closure(move |m| if *m.cell() == 0 { end + 1 } else { next }),
+        Op::JumpIfDataIsNotZero(start) => closure(move |m| {
+            if *m.cell() != 0 {
+                m.repeat(start + 1)
+            } else {
+                next
+            }
+        }),
+        Op::LoopSetToZero => closure(move |m| {
+            *m.cell() = 0;
+            next
+        }),
+        Op::LoopMovePtr(n) => closure(move |m| match scan_loop(m.memory, &mut m.data_ptr, n) {
+            Ok(()) => next,
+            Err(e) => m.fail(e),
+        }),
+        Op::LoopMoveData(n) => closure(move |m| {
+            if *m.cell() != 0 {
+                let from = m.data_ptr;
+                if let Err(e) = move_ptr(&mut m.data_ptr, n, m.memory.len()) {
+                    // The unoptimized loop decrements the cell before it moves off the tape.
+                    m.memory[from] = m.memory[from].wrapping_sub(1);
+                    return m.fail(e);
+                }
+                let to = std::mem::replace(&mut m.data_ptr, from);
+                m.memory[to] = m.memory[to].wrapping_add(m.memory[from]);
+                m.memory[from] = 0;
+            }
+            next
+        }),
+        Op::DebugDump(n) => {
+            closure(
+                move |m| match dump_state(&mut m.debug, n, m.data_ptr, m.memory) {
+                    Ok(()) => next,
+                    Err(e) => m.fail(e),
+                },
+            )
+        }
+        Op::DefineProcedure(end) => closure(move |m| {
+            let number = *m.cell();
+            m.procedures.define(number, pc);
+            end + 1
+        }),
+        Op::EndProcedure => closure(|m| m.procedures.ret() + 1),
+        Op::CallProcedure => closure(move |m| {
+            let number = *m.cell();
+            match m.procedures.call(number, pc) {
+                Ok(start) => start + 1,
+                Err(e) => m.fail(e),
+            }
+        }),
+        Op::End => closure(|_| STOP),
+        Op::Store => closure(move |m| {
+            m.storage = *m.cell();
+            next
+        }),
+        Op::Load => closure(move |m| {
+            *m.cell() = m.storage;
+            next
+        }),
+        Op::ShiftLeft(_) | Op::ShiftRight(_) | Op::Not | Op::Xor | Op::And | Op::Or => {
+            closure(move |m| {
+                *m.cell() = apply_bitwise(&op, *m.cell(), m.storage);
+                next
+            })
+        }
+        // `Engine` does not compile brainfork's `Y` for this tier.
+        Op::Fork => unreachable!(),
+    }
+}
+
+// One op of a `Block`, at an offset from the pointer where the block starts.
+#[derive(Clone, Copy)]
+enum Action {
+    Add(isize, u8),
+    SetToZero(isize),
+    // `Op::LoopMoveData` at the first offset, which moves the cell by the second.
+    MoveData(isize, isize),
+}
+
+// A run of ops which only move the pointer and change cells.
+struct Block {
+    actions: Vec<Action>,
+    // How far the block moves the pointer, and the lowest and highest offset it may reach.
+    moved: isize,
+    lo: isize,
+    hi: isize,
+    // The closures of the ops, which are run one by one when the block may leave the tape, so that
+    // it fails in the same state as they do.
+    closures: Vec<Closure>,
+}
+
+impl Block {
+    // The block of `ops`, which start at `pc`, if they are all ops it can run.
+    fn new(ops: &[Op], pc: usize) -> Option<Block> {
+        if ops.is_empty() {
+            return None;
+        }
+        let mut actions = Vec::new();
+        let (mut offset, mut lo, mut hi) = (0, 0, 0);
+        for op in ops {
+            match *op {
+                Op::IncPtr(n) => offset += n as isize,
+                Op::DecPtr(n) => offset -= n as isize,
+                Op::IncData(n) => actions.push(Action::Add(offset, n as u8)),
+                Op::DecData(n) => actions.push(Action::Add(offset, (n as u8).wrapping_neg())),
+                Op::LoopSetToZero => actions.push(Action::SetToZero(offset)),
+                Op::LoopMoveData(n) => {
+                    actions.push(Action::MoveData(offset, n));
+                    lo = std::cmp::min(lo, offset + n);
+                    hi = std::cmp::max(hi, offset + n);
+                }
+                _ => return None,
+            }
+            lo = std::cmp::min(lo, offset);
+            hi = std::cmp::max(hi, offset);
+        }
+        Some(Block {
+            actions,
+            moved: offset,
+            lo,
+            hi,
+            closures: ops
+                .iter()
+                .enumerate()
+                .map(|(i, op)| compile(*op, pc + i))
+                .collect(),
+        })
+    }
+
+    // Runs the block, and returns whether the program goes on.
+    fn run(&self, m: &mut Machine<'_>) -> bool {
+        let data_ptr = m.data_ptr as isize;
+        if data_ptr + self.lo < 0 || data_ptr + self.hi >= m.memory.len() as isize {
+            return self.closures.iter().all(|closure| closure(m) != STOP);
+        }
+        // Every cell the block reaches is on the tape.
+        let memory = unsafe { m.memory.as_mut_ptr().offset(data_ptr) };
+        for action in &self.actions {
+            unsafe {
+                match *action {
+                    Action::Add(offset, n) => {
+                        let cell = memory.offset(offset);
+                        *cell = (*cell).wrapping_add(n);
+                    }
+                    Action::SetToZero(offset) => *memory.offset(offset) = 0,
+                    Action::MoveData(offset, n) => {
+                        let from = memory.offset(offset);
+                        let to = memory.offset(offset + n);
+                        *to = (*to).wrapping_add(*from);
+                        *from = 0;
+                    }
+                }
+            }
+        }
+        m.data_ptr = (data_ptr + self.moved) as usize;
+        true
+    }
+}
+
+// The closures of `ops`. Only those at the pcs the program reaches, which are the starts of blocks
+// and loops, have to run the ops after them.
+fn compile_program(ops: &[Op]) -> Vec<Closure> {
+    let mut closures: Vec<Closure> = ops
+        .iter()
+        .enumerate()
+        .map(|(pc, op)| compile(*op, pc))
+        .collect();
+    let mut pc = 0;
+    while pc < ops.len() {
+        let mut end = pc;
+        while end < ops.len() && Block::new(&ops[end..=end], end).is_some() {
+            end += 1;
+        }
+        if end > pc {
+            let block = Block::new(&ops[pc..end], pc).unwrap();
+            // The `]` after a block is run with it.
+            closures[pc] = match ops.get(end) {
+                Some(Op::JumpIfDataIsNotZero(start)) => {
+                    let start = *start;
+                    closure(move |m| match block.run(m) {
+                        false => STOP,
+                        true if *m.cell() != 0 => m.repeat(start + 1),
+                        true => end + 1,
+                    })
+                }
+                _ => closure(move |m| if block.run(m) { end } else { STOP }),
+            };
+        }
+        pc = std::cmp::max(end, pc + 1);
+    }
+    for (pc, op) in ops.iter().enumerate() {
+        if let Op::JumpIfDataIsZero(end) = *op {
+            if let Some(block) = Block::new(&ops[pc + 1..end], pc + 1) {
+                closures[pc] = closure(move |m| {
+                    while *m.cell() != 0 {
+                        if !block.run(m) {
+                            return STOP;
+                        }
+                        if let Err(e) = check_cancel(m.cancel) {
+                            return m.fail(e);
+                        }
+                    }
+                    end + 1
+                });
+            }
+        }
+    }
+    closures
+}
+
+pub(crate) struct Interpreter4 {
+    closures: Vec<Closure>,
+    eof: EofPolicy,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Brainfuck for Interpreter4 {
+    const MEMORY_SIZE: usize = 300000;
+
+    fn with_options(s: &[u8], options: &Options) -> Interpreter4 {
+        let instructions = filter_instructions(s, options);
+        let closures = compile_program(&translate_program(&instructions, true));
+        Interpreter4 {
+            closures,
+            eof: options.eof,
+            cancel: options.cancel.clone(),
+        }
+    }
+
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        mut write: impl Write,
+        mut debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let mut input = input_bytes(read);
+        let mut machine = Machine {
+            memory: &mut tape.memory,
+            data_ptr: tape.data_ptr,
+            input: &mut input,
+            output: &mut write,
+            debug: &mut debug,
+            eof: self.eof,
+            procedures: Procedures::new(),
+            storage: 0,
+            cancel: self.cancel.as_deref(),
+            error: None,
+        };
+        let mut pc = 0;
+        while pc < self.closures.len() {
+            pc = self.closures[pc](&mut machine);
+        }
+        tape.data_ptr = machine.data_ptr;
+        match machine.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // The output, the kind of error, and the final tape of `s` on a tape of `len` cells.
+    fn run<P: Brainfuck>(s: &[u8], len: usize) -> (Vec<u8>, Option<String>, Tape) {
+        let mut out = Vec::new();
+        let mut tape = Tape::new(len);
+        let result = P::new(s).run_on_tape(&[] as &[u8], &mut out, std::io::sink(), &mut tape);
+        (out, result.err().map(|e| error_kind(&e)), tape)
+    }
+
+    #[test]
+    fn blocks_test() {
+        // Blocks, and loops of them, which reach the ends of the tape fail where the unoptimized
+        // program does.
+        for s in &[
+            &b"++[->+>+<<]>>."[..],
+            b"+++[>++<-]>.",
+            b"+>++>+++<<[->>>+<[-]<]",
+            b">>+<<-[+>+>+<]",
+            b"+++>+[<[->+<]<+>]",
+            b"+[>+<-]>>+<[-]<<+",
+        ] {
+            for len in 1..6 {
+                assert_eq!(
+                    run::<Interpreter4>(s, len),
+                    run::<Interpreter1>(s, len),
+                    "{} on {} cells",
+                    String::from_utf8_lossy(s),
+                    len
+                );
+            }
+        }
+    }
+}
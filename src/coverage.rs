@@ -0,0 +1,226 @@
+// Code coverage of brainfuck programs, written in the lcov tracefile format.
+//
+// Lines are the lines of the original source which contain instructions. Each loop is also
+// reported as a branch block, whose branches are "entered" and "skipped" at its `[`.
+use crate::brainfuck::*;
+use crate::profile::{self, LoopProfile, ObservedProgram, Profile};
+use crate::testcase;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+struct FileCoverage {
+    source: Vec<u8>,
+    lines: profile::LineIndex,
+    positions: Vec<usize>,
+    counts: Vec<u64>,
+    // Sorted by the position of `[`.
+    loops: Vec<LoopProfile>,
+}
+
+#[derive(Default)]
+pub struct Coverage {
+    files: BTreeMap<PathBuf, FileCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // Adds the counts of a run of the program at `path`. Runs of the same file are merged, so they
+    // must be runs of the same source.
+    pub fn add(&mut self, path: impl AsRef<Path>, profile: &Profile) -> Result<()> {
+        let mut loops = profile.loops();
+        loops.sort_by_key(|l| l.start);
+        let file = self
+            .files
+            .entry(path.as_ref().to_path_buf())
+            .or_insert_with(|| FileCoverage {
+                source: profile.source().to_vec(),
+                lines: profile::LineIndex::new(profile.source()),
+                positions: profile.positions().to_vec(),
+                counts: vec![0; profile.counts().len()],
+                loops: loops
+                    .iter()
+                    .map(|l| LoopProfile {
+                        entries: 0,
+                        iterations: 0,
+                        ..*l
+                    })
+                    .collect(),
+            });
+        if file.source != profile.source() || file.positions != profile.positions() {
+            return Err(failure::format_err!(
+                "{} has changed between runs",
+                path.as_ref().display()
+            ));
+        }
+        for (total, count) in file.counts.iter_mut().zip(profile.counts()) {
+            *total += count;
+        }
+        for (total, l) in file.loops.iter_mut().zip(loops) {
+            total.entries += l.entries;
+            total.iterations += l.iterations;
+        }
+        Ok(())
+    }
+
+    pub fn write_lcov(&self, mut w: impl Write) -> Result<()> {
+        for (path, file) in &self.files {
+            writeln!(w, "TN:")?;
+            writeln!(w, "SF:{}", path.display())?;
+
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (block, l) in file.loops.iter().enumerate() {
+                let executed = file.counts[l.start];
+                let skipped = executed - l.entries;
+                for (branch, taken) in [l.entries, skipped].iter().enumerate() {
+                    branches_found += 1;
+                    if executed == 0 {
+                        writeln!(w, "BRDA:{},{},{},-", l.line, block, branch)?;
+                    } else {
+                        if *taken > 0 {
+                            branches_hit += 1;
+                        }
+                        writeln!(w, "BRDA:{},{},{},{}", l.line, block, branch, taken)?;
+                    }
+                }
+            }
+            writeln!(w, "BRF:{}", branches_found)?;
+            writeln!(w, "BRH:{}", branches_hit)?;
+
+            // A line is as hot as its most executed instruction.
+            let mut lines = BTreeMap::new();
+            for (position, count) in file.positions.iter().zip(&file.counts) {
+                let (line, _) = file.lines.line_column(*position);
+                let hits = lines.entry(line).or_insert(0);
+                *hits = std::cmp::max(*hits, *count);
+            }
+            for (line, hits) in &lines {
+                writeln!(w, "DA:{},{}", line, hits)?;
+            }
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(w, "LH:{}", lines.values().filter(|hits| **hits > 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+// Runs every test case in `dir` (see `testcase`), set up and fed as it asks, and collects the
+// coverage. A program which fails as its test expects counts up to its error.
+pub fn run_suite(dir: impl AsRef<Path>, options: &Options) -> Result<Coverage> {
+    let mut coverage = Coverage::new();
+    for test in crate::testcase::load_dir(dir)? {
+        let test = test?;
+        let path = test.path.display();
+        let (options, tape_size) =
+            testcase::configure(&test, Interpreter1::MEMORY_SIZE, options)
+                .map_err(|reason| failure::format_err!("{}: {}", path, reason))?;
+        let program = match ObservedProgram::new(&test.program, &options, None) {
+            Ok(program) => program,
+            // The program never runs, so it has no coverage.
+            Err(ref e) if Some(error_kind(e)) == test.expected.expect_error => continue,
+            Err(e) => return Err(failure::format_err!("{}: {}", path, e)),
+        };
+        let profile = profile::profile_on_tape(
+            &test.program,
+            &program,
+            test.expected.feed_in.as_bytes(),
+            std::io::sink(),
+            std::io::stderr(),
+            &mut Tape::new(tape_size),
+        );
+        let error = profile.error().map(error_kind);
+        if error != test.expected.expect_error {
+            return Err(match error {
+                Some(error) => failure::format_err!("{}: failed with error {}", path, error),
+                None => failure::format_err!("{}: expected an error, but succeeded", path),
+            });
+        }
+        coverage.add(&test.path, &profile)?;
+    }
+    Ok(coverage)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn lcov_test() {
+        let s = b"+[-]\n[\n+\n]>\n";
+        let profile = profile::profile(
+            s,
+            &[] as &[u8],
+            std::io::sink(),
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        let mut coverage = Coverage::new();
+        coverage.add("a.bf", &profile).unwrap();
+        coverage.add("a.bf", &profile).unwrap();
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov).unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:
+SF:a.bf
+BRDA:1,0,0,2
+BRDA:1,0,1,0
+BRDA:2,1,0,0
+BRDA:2,1,1,2
+BRF:4
+BRH:2
+DA:1,2
+DA:2,2
+DA:3,0
+DA:4,2
+LF:4
+LH:3
+end_of_record
+"
+        );
+
+        let changed = profile::profile(
+            b"+",
+            &[] as &[u8],
+            std::io::sink(),
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            coverage.add("a.bf", &changed).unwrap_err().to_string(),
+            "a.bf has changed between runs"
+        );
+    }
+
+    #[test]
+    fn run_suite_test() {
+        let dir = "src/testcases";
+        let coverage = run_suite(dir, &Options::default()).unwrap();
+        assert_eq!(
+            coverage.files.len(),
+            crate::testcase::load_dir(dir).unwrap().len()
+        );
+        // The expected error comes after every instruction has run.
+        let file = &coverage.files[Path::new(dir).join("scanloops-edge.bf").as_path()];
+        assert!(file.counts.iter().all(|count| *count > 0));
+
+        let dir =
+            std::env::temp_dir().join(format!("brainfuck-coverage-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bf"), b"+[").unwrap();
+        std::fs::write(dir.join("a.test"), r#"{"feed-in": ""}"#).unwrap();
+        let e = run_suite(&dir, &Options::default()).err().unwrap();
+        assert!(e.to_string().ends_with("a.bf: unmatched '[' at 1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+// Languages which are brainfuck with its eight instructions spelled differently, such as Ook!,
+// Blub or Alphuck. Engines read programs through the dialect in their `Options`, so that a
+// program in any dialect runs, and is profiled, unchanged.
+//
+// https://esolangs.org/wiki/Trivial_brainfuck_substitution
+use serde_derive::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+const INSTRUCTIONS: &[u8] = b"><+-.,[]";
+// The instructions of extensions of brainfuck. A dialect has all or none of those of each.
+const PROCEDURE_INSTRUCTIONS: &[u8] = b"():";
+const FORK_INSTRUCTIONS: &[u8] = b"Y";
+const EXTENDED_INSTRUCTIONS: &[u8] = b"@$!}{~^&|";
+const EXTENSIONS: &[&[u8]] = &[
+    PROCEDURE_INSTRUCTIONS,
+    FORK_INSTRUCTIONS,
+    EXTENDED_INSTRUCTIONS,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dialect {
+    pub name: String,
+    // The token of each instruction, keyed by the instruction in brainfuck. Words in a token are
+    // separated by whitespace, and match words separated by any whitespace in a program.
+    pub tokens: BTreeMap<String, String>,
+    // Written between tokens by `emit`.
+    #[serde(default)]
+    pub separator: String,
+    // Number of tokens on each line written by `emit`. All tokens are on one line if unset.
+    #[serde(
+        rename = "line-width",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub line_width: Option<usize>,
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect::brainfuck()
+    }
+}
+
+impl Dialect {
+    fn from_tokens(
+        name: &str,
+        tokens: &[&str],
+        separator: &str,
+        line_width: Option<usize>,
+    ) -> Dialect {
+        Dialect {
+            name: name.to_string(),
+            tokens: INSTRUCTIONS
+                .iter()
+                .zip(tokens)
+                .map(|(i, token)| ((*i as char).to_string(), token.to_string()))
+                .collect(),
+            separator: separator.to_string(),
+            line_width,
+        }
+    }
+
+    pub fn brainfuck() -> Dialect {
+        Dialect::from_tokens(
+            "brainfuck",
+            &[">", "<", "+", "-", ".", ",", "[", "]"],
+            "",
+            None,
+        )
+    }
+
+    // https://esolangs.org/wiki/Ook!
+    pub fn ook() -> Dialect {
+        Dialect::from_tokens(
+            "ook",
+            &[
+                "Ook. Ook?",
+                "Ook? Ook.",
+                "Ook. Ook.",
+                "Ook! Ook!",
+                "Ook! Ook.",
+                "Ook. Ook!",
+                "Ook! Ook?",
+                "Ook? Ook!",
+            ],
+            " ",
+            Some(8),
+        )
+    }
+
+    // Brainfuck with procedures: `(` ... `)` defines the procedure numbered by the current cell,
+    // and `:` calls it.
+    // https://esolangs.org/wiki/Pbrain
+    pub fn pbrain() -> Dialect {
+        Dialect::with_extension("pbrain", PROCEDURE_INSTRUCTIONS)
+    }
+
+    // Brainfuck with threads: `Y` forks the machine.
+    // https://esolangs.org/wiki/Brainfork
+    pub fn brainfork() -> Dialect {
+        Dialect::with_extension("brainfork", FORK_INSTRUCTIONS)
+    }
+
+    // Extended Brainfuck Type I, with a storage register: `$` stores the current cell, and `!`
+    // loads it. `{` and `}` shift the cell left and right, `~` inverts it, and `^`, `&` and `|`
+    // combine it with storage. `@` ends the program.
+    // https://esolangs.org/wiki/Extended_Brainfuck
+    pub fn extended_type_1() -> Dialect {
+        Dialect::with_extension("extended-type-1", EXTENDED_INSTRUCTIONS)
+    }
+
+    // Brainfuck with `extension`, whose instructions are spelled as themselves.
+    fn with_extension(name: &str, extension: &[u8]) -> Dialect {
+        let mut dialect = Dialect::brainfuck();
+        dialect.name = name.to_string();
+        for i in extension {
+            let instruction = (*i as char).to_string();
+            dialect.tokens.insert(instruction.clone(), instruction);
+        }
+        dialect
+    }
+
+    pub fn builtins() -> Vec<Dialect> {
+        vec![
+            Dialect::brainfuck(),
+            Dialect::ook(),
+            Dialect::pbrain(),
+            Dialect::brainfork(),
+            Dialect::extended_type_1(),
+        ]
+    }
+
+    // Loads a dialect from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Dialect> {
+        let dialect: Dialect = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        dialect.validate()?;
+        Ok(dialect)
+    }
+
+    // The builtin dialect named `name`, or else the dialect in the file at `name`.
+    pub fn find(name: &str) -> Result<Dialect> {
+        match Dialect::builtins().into_iter().find(|d| d.name == name) {
+            Some(dialect) => Ok(dialect),
+            None if Path::new(name).is_file() => Dialect::load(name),
+            None => Err(failure::format_err!("unknown dialect: {}", name)),
+        }
+    }
+
+    // The builtin dialect named by the extension of `path`, or brainfuck.
+    pub fn from_path(path: impl AsRef<Path>) -> Dialect {
+        let extension = path.as_ref().extension().and_then(|e| e.to_str());
+        Dialect::builtins()
+            .into_iter()
+            .find(|d| Some(d.name.as_str()) == extension)
+            .unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for i in self.instructions() {
+            let instruction = (i as char).to_string();
+            match self.tokens.get(&instruction) {
+                Some(token) if token.split_whitespace().next().is_some() => (),
+                Some(_) => return Err(failure::format_err!("empty token for {}", instruction)),
+                None => return Err(failure::format_err!("no token for {}", instruction)),
+            }
+        }
+        if let Some(key) = self.tokens.keys().find(|k| {
+            k.len() != 1
+                || !EXTENSIONS
+                    .iter()
+                    .chain(&[INSTRUCTIONS])
+                    .any(|instructions| instructions.contains(&k.as_bytes()[0]))
+        }) {
+            return Err(failure::format_err!("{} is not an instruction", key));
+        }
+        if self.line_width == Some(0) {
+            return Err(failure::err_msg("line-width must be at least 1"));
+        }
+        Ok(())
+    }
+
+    fn has_extension(&self, extension: &[u8]) -> bool {
+        extension
+            .iter()
+            .any(|i| self.tokens.contains_key(&(*i as char).to_string()))
+    }
+
+    // Whether the dialect has pbrain's procedures.
+    pub fn has_procedures(&self) -> bool {
+        self.has_extension(PROCEDURE_INSTRUCTIONS)
+    }
+
+    // Whether the dialect has Brainfork's `Y`.
+    pub fn has_fork(&self) -> bool {
+        self.has_extension(FORK_INSTRUCTIONS)
+    }
+
+    // The instructions which the dialect has tokens for, or is missing tokens for.
+    fn instructions(&self) -> Vec<u8> {
+        let mut instructions = INSTRUCTIONS.to_vec();
+        for extension in EXTENSIONS {
+            if self.has_extension(extension) {
+                instructions.extend_from_slice(extension);
+            }
+        }
+        instructions
+    }
+
+    fn token(&self, instruction: u8) -> &str {
+        &self.tokens[&(instruction as char).to_string()]
+    }
+
+    // Splits `s` into tokens, and returns the brainfuck instruction of each, with the offset of
+    // the token in `s`. Anything which is not a token is a comment. Where several tokens start
+    // at the same offset, the longest wins. With `debug_hash`, `#` is a token too.
+    pub fn tokenize(&self, s: &[u8], debug_hash: bool) -> (Vec<u8>, Vec<usize>) {
+        let mut tokens: Vec<(Vec<&[u8]>, u8)> = self
+            .instructions()
+            .into_iter()
+            .map(|i| {
+                let words = self.token(i).split_whitespace().map(str::as_bytes);
+                (words.collect(), i)
+            })
+            .collect();
+        if debug_hash {
+            tokens.push((vec![b"#"], b'#'));
+        }
+        let mut instructions = Vec::new();
+        let mut positions = Vec::new();
+        let mut offset = 0;
+        while offset < s.len() {
+            let longest = tokens
+                .iter()
+                .filter_map(|(words, i)| match_words(&s[offset..], words).map(|len| (len, *i)))
+                .max_by_key(|(len, _)| *len);
+            match longest {
+                Some((len, instruction)) => {
+                    instructions.push(instruction);
+                    positions.push(offset);
+                    offset += len;
+                }
+                None => offset += 1,
+            }
+        }
+        (instructions, positions)
+    }
+
+    // Writes the instructions of the brainfuck program `s` in this dialect. Fails on an instruction
+    // of an extension which the dialect does not have, rather than change what the program does.
+    pub fn emit(&self, s: &[u8]) -> Result<Vec<u8>> {
+        let known = self.instructions();
+        if let Some(i) = s
+            .iter()
+            .find(|c| !known.contains(c) && EXTENSIONS.iter().any(|e| e.contains(c)))
+        {
+            return Err(failure::format_err!(
+                "{} has no `{}` instruction",
+                self.name,
+                *i as char
+            ));
+        }
+        let mut out = Vec::new();
+        let instructions = s.iter().filter(|c| known.contains(c));
+        for (n, i) in instructions.enumerate() {
+            if n > 0 {
+                match self.line_width {
+                    Some(width) if n % width == 0 => out.push(b'\n'),
+                    _ => out.extend_from_slice(self.separator.as_bytes()),
+                }
+            }
+            out.extend_from_slice(self.token(*i).as_bytes());
+        }
+        if !out.is_empty() {
+            out.push(b'\n');
+        }
+        Ok(out)
+    }
+}
+
+// The length of the match of `words` at the start of `s`, allowing any whitespace between them.
+fn match_words(s: &[u8], words: &[&[u8]]) -> Option<usize> {
+    let mut len = 0;
+    for (n, word) in words.iter().enumerate() {
+        if n > 0 {
+            len += s[len..]
+                .iter()
+                .take_while(|c| c.is_ascii_whitespace())
+                .count();
+        }
+        if !s[len..].starts_with(word) {
+            return None;
+        }
+        len += word.len();
+    }
+    Some(len)
+}
+
+impl std::fmt::Display for Dialect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl std::str::FromStr for Dialect {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Dialect> {
+        Dialect::find(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn ook_test() {
+        let ook = b"Ook. Ook. Ook. Ook. Ook! Ook?  The loop.\nOok. Ook? Ook. Ook. Ook?\nOok. Ook! Ook! Ook? Ook!\nOok. Ook? Ook! Ook.";
+        let (s, positions) = Dialect::ook().tokenize(ook, false);
+        assert_eq!(s, b"++[>+<-]>.");
+        assert_eq!(&positions[..4], &[0, 10, 20, 41]);
+        let mut out = Vec::new();
+        crate::run_default(&s, &[] as &[u8], &mut out).unwrap();
+        assert_eq!(out, b"\x02");
+
+        let emitted = Dialect::ook().emit(b"++[>+<-] comment >.").unwrap();
+        assert_eq!(
+            String::from_utf8(emitted.clone()).unwrap(),
+            "Ook. Ook. Ook. Ook. Ook! Ook? Ook. Ook? Ook. Ook. Ook? Ook. Ook! Ook! Ook? Ook!\n\
+             Ook. Ook? Ook! Ook.\n"
+        );
+        assert_eq!(Dialect::ook().tokenize(&emitted, false).0, s);
+    }
+
+    #[test]
+    fn dialect_test() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("dialects");
+        let alphuck = Dialect::load(dir.join("alphuck.json")).unwrap();
+        assert_eq!(alphuck.tokenize(b"ee pa e ci s aj", false).0, b"++[>+<-]>.");
+        assert_eq!(alphuck.emit(b"+[-]").unwrap(), b"epis\n");
+        for path in glob::glob(&format!("{}/*.json", dir.display())).unwrap() {
+            let dialect = Dialect::load(path.unwrap()).unwrap();
+            let program = dialect.emit(b"+[->,.<]").unwrap();
+            assert_eq!(dialect.tokenize(&program, false).0, b"+[->,.<]");
+        }
+
+        // The longest token wins.
+        let mut dialect = Dialect::brainfuck();
+        dialect.tokens.insert("-".to_string(), "++".to_string());
+        assert_eq!(dialect.tokenize(b"+++ #", true).0, b"-+#");
+
+        dialect.tokens.remove("-");
+        assert!(dialect.validate().is_err());
+
+        assert_eq!(Dialect::pbrain().tokenize(b"(+): #", false).0, b"(+):");
+        assert_eq!(
+            Dialect::ook().emit(b"(+):").unwrap_err().to_string(),
+            "ook has no `(` instruction"
+        );
+        assert_eq!(
+            Dialect::brainfuck()
+                .emit(b"+(>+++<):.")
+                .unwrap_err()
+                .to_string(),
+            "brainfuck has no `(` instruction"
+        );
+        assert_eq!(Dialect::pbrain().emit(b"+(-):").unwrap(), b"+(-):\n");
+        // Dropping `Y` would leave a loop which never ends.
+        assert_eq!(
+            Dialect::brainfuck().emit(b"+[Y]").unwrap_err().to_string(),
+            "brainfuck has no `Y` instruction"
+        );
+        assert_eq!(
+            Dialect::ook().emit(b"+$>!").unwrap_err().to_string(),
+            "ook has no `$` instruction"
+        );
+        assert_eq!(
+            Dialect::extended_type_1().emit(b"+$>!@").unwrap(),
+            b"+$>!@\n"
+        );
+        let mut pbrain = Dialect::pbrain();
+        pbrain.tokens.remove(")");
+        assert!(pbrain.validate().is_err());
+
+        assert_eq!(Dialect::from_path("a/hello.ook"), Dialect::ook());
+        assert_eq!(Dialect::from_path("a/hello.bf"), Dialect::brainfuck());
+    }
+}
@@ -0,0 +1,300 @@
+// Differential testing: runs a program on every engine and compares what they did.
+use crate::brainfuck::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    // The final tape without its trailing zeros, since engines use tapes of different sizes.
+    pub tape: Vec<u8>,
+    pub data_ptr: usize,
+    // The kind of error the program failed with, as given by `error_kind`, or "panic".
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub engine: Engine,
+    pub reference: Engine,
+    pub what: String,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} diverges from {}: {}",
+            self.engine, self.reference, self.what
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct DifferentialReport {
+    pub outcomes: Vec<(Engine, Outcome)>,
+    pub divergence: Option<Divergence>,
+}
+
+fn run_engine(
+    engine: Engine,
+    s: &[u8],
+    input: &[u8],
+    options: &Options,
+    tape_size: usize,
+) -> Outcome {
+    let mut output = Vec::new();
+    let mut tape = Tape::new(tape_size);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        engine.run_on_tape(s, options, input, &mut output, std::io::sink(), &mut tape)
+    }));
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(error_kind(&e)),
+        Err(_) => Some("panic".to_string()),
+    };
+    let len = tape
+        .memory
+        .iter()
+        .rposition(|cell| *cell != 0)
+        .map_or(0, |i| i + 1);
+    tape.memory.truncate(len);
+    Outcome {
+        output,
+        tape: tape.memory,
+        data_ptr: tape.data_ptr,
+        error,
+    }
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter().zip(b).position(|(a, b)| a != b).or_else(|| {
+        if a.len() != b.len() {
+            Some(std::cmp::min(a.len(), b.len()))
+        } else {
+            None
+        }
+    })
+}
+
+fn compare(reference: &Outcome, outcome: &Outcome) -> Option<String> {
+    if outcome.error != reference.error {
+        return Some(format!(
+            "error is {:?}, expected {:?}",
+            outcome.error, reference.error
+        ));
+    }
+    if let Some(i) = first_difference(&reference.output, &outcome.output) {
+        return Some(format!(
+            "output differs at byte {}: {:?}, expected {:?}",
+            i,
+            outcome.output.get(i),
+            reference.output.get(i)
+        ));
+    }
+    if let Some(i) = first_difference(&reference.tape, &outcome.tape) {
+        return Some(format!(
+            "tape differs at cell {}: {}, expected {}",
+            i,
+            outcome.tape.get(i).cloned().unwrap_or(0),
+            reference.tape.get(i).cloned().unwrap_or(0)
+        ));
+    }
+    if outcome.data_ptr != reference.data_ptr {
+        return Some(format!(
+            "data pointer is {}, expected {}",
+            outcome.data_ptr, reference.data_ptr
+        ));
+    }
+    None
+}
+
+// Like `run_engine`, but cancels the run once it takes longer than `budget`.
+fn run_engine_within(
+    engine: Engine,
+    s: &[u8],
+    input: &[u8],
+    options: &Options,
+    tape_size: usize,
+    budget: Duration,
+) -> Outcome {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let options = Options {
+        cancel: Some(cancel.clone()),
+        ..options.clone()
+    };
+    // Dropping `done` wakes the timer up early.
+    let (done, timeout) = mpsc::channel::<()>();
+    let timer = thread::spawn(move || {
+        if timeout.recv_timeout(budget) == Err(mpsc::RecvTimeoutError::Timeout) {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    });
+    let outcome = run_engine(engine, s, input, &options, tape_size);
+    drop(done);
+    timer.join().unwrap();
+    outcome
+}
+
+fn differential_run_by(mut run: impl FnMut(Engine) -> Outcome) -> DifferentialReport {
+    let mut engines = Engine::all().into_iter();
+    let reference = engines.next().unwrap();
+    let mut report = DifferentialReport {
+        outcomes: vec![(reference, run(reference))],
+        divergence: None,
+    };
+    for engine in engines {
+        let outcome = run(engine);
+        if report.divergence.is_none() {
+            report.divergence = compare(&report.outcomes[0].1, &outcome).map(|what| Divergence {
+                engine,
+                reference,
+                what,
+            });
+        }
+        report.outcomes.push((engine, outcome));
+    }
+    report
+}
+
+// Runs `s` on every engine, and reports the first one which does not behave like the first
+// engine, `Interpreter1`.
+pub fn differential_run(s: &[u8], input: &[u8], options: &Options) -> DifferentialReport {
+    differential_run_by(|engine| run_engine(engine, s, input, options, engine.memory_size()))
+}
+
+// Like `differential_run`, for programs which may never end. Each engine is cancelled after
+// `budget`, and gets a tape of the same size, so that programs running off the end of the tape
+// fail at the same cell. Returns `None` if any engine was cancelled, since the engines then
+// stopped at different points.
+pub fn differential_run_within(
+    s: &[u8],
+    input: &[u8],
+    options: &Options,
+    budget: Duration,
+) -> Option<DifferentialReport> {
+    let tape_size = Engine::Interpreter1.memory_size();
+    let report = differential_run_by(|engine| {
+        run_engine_within(engine, s, input, options, tape_size, budget)
+    });
+    let cancelled = report
+        .outcomes
+        .iter()
+        .any(|(_, outcome)| outcome.error.as_deref() == Some("cancelled"));
+    if cancelled {
+        None
+    } else {
+        Some(report)
+    }
+}
+
+// Runs `differential_run` on every `*.bf` in `dir`, sorted by path. A program is fed with the
+// `feed-in` of its `.test` file if there is one.
+pub fn differential_run_dir(
+    dir: impl AsRef<Path>,
+    options: &Options,
+) -> Result<Vec<(PathBuf, DifferentialReport)>> {
+    crate::testcase::programs(dir)?
+        .into_iter()
+        .map(|path| {
+            let program = std::fs::read(&path)?;
+            let input = crate::testcase::feed_in(&path)?;
+            let report = differential_run(&program, &input, options);
+            Ok((path, report))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn differential_run_test() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/testcases");
+        let reports = differential_run_dir(dir, &Options::default()).unwrap();
+        assert!(reports.len() > 5);
+        for (path, report) in reports {
+            assert_eq!(report.outcomes.len(), Engine::all().len());
+            assert!(
+                report.divergence.is_none(),
+                "{}: {}",
+                path.display(),
+                report.divergence.unwrap()
+            );
+        }
+
+        // Every engine fails in the same way when input runs out.
+        let report = differential_run(b">+>,,", b"x", &Options::default());
+        assert_eq!(report.divergence, None);
+        assert_eq!(report.outcomes.len(), Engine::all().len());
+        assert_eq!(
+            report.outcomes[0].1,
+            Outcome {
+                output: vec![],
+                tape: vec![0, 1, b'x'],
+                data_ptr: 2,
+                error: Some("input-unavailable".to_string()),
+            }
+        );
+
+        // ... and when the data pointer leaves the tape, even in the middle of an optimized loop.
+        let report = differential_run(b"+[-<+>]", b"", &Options::default());
+        assert_eq!(report.divergence, None);
+        assert_eq!(
+            report.outcomes[0].1,
+            Outcome {
+                output: vec![],
+                tape: vec![],
+                data_ptr: 0,
+                error: Some("pointer-out-of-bounds".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn differential_run_within_test() {
+        let budget = Duration::from_millis(50);
+        assert!(differential_run_within(b"+[]", b"", &Options::default(), budget).is_none());
+
+        // Every engine runs off the end of the same tape.
+        let report = differential_run_within(b"+[>+]", b"", &Options::default(), budget).unwrap();
+        assert_eq!(report.divergence, None);
+        let size = Engine::Interpreter1.memory_size();
+        assert_eq!(report.outcomes[0].1.tape.len(), size);
+        assert_eq!(report.outcomes[0].1.data_ptr, size - 1);
+        assert_eq!(
+            report.outcomes[0].1.error.as_deref(),
+            Some("pointer-out-of-bounds")
+        );
+    }
+
+    #[test]
+    fn compare_test() {
+        let reference = Outcome {
+            output: b"abc".to_vec(),
+            tape: vec![1, 2],
+            data_ptr: 1,
+            error: None,
+        };
+        let mut outcome = reference.clone();
+        assert_eq!(compare(&reference, &outcome), None);
+        outcome.tape.push(3);
+        assert_eq!(
+            compare(&reference, &outcome),
+            Some("tape differs at cell 2: 3, expected 0".to_string())
+        );
+        outcome.output.truncate(2);
+        assert_eq!(
+            compare(&reference, &outcome),
+            Some("output differs at byte 2: None, expected Some(99)".to_string())
+        );
+    }
+}
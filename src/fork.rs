@@ -0,0 +1,424 @@
+// Brainfork, which is brainfuck with `Y` to fork the machine. The cell of the parent is zeroed, and
+// the child, with a copy of the tape, continues one cell to the right, where its cell is set to 1.
+// Threads run the `Op`s of the optimized interpreters, either taking turns on one OS thread, which
+// is deterministic, or each on an OS thread of its own.
+//
+// https://esolangs.org/wiki/Brainfork
+use crate::brainfuck::*;
+use crate::scan::scan_loop;
+use std::io::Read;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// Maximum number of threads running at once.
+pub const MAX_THREADS: usize = 1024;
+
+// Number of ops an OS thread runs between checks for a failure of another thread.
+const QUANTUM: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduling {
+    // Threads take turns running one op each, in the order they were created, so that a program
+    // always writes the same output.
+    RoundRobin,
+    // Each thread runs on an OS thread. Bytes written by different threads are interleaved in any
+    // order.
+    OsThreads,
+}
+
+pub struct Brainfork {
+    ops: Vec<Op>,
+    eof: EofPolicy,
+}
+
+#[derive(Clone)]
+struct Thread {
+    pc: usize,
+    tape: Tape,
+    procedures: Procedures,
+    storage: u8,
+    halted: bool,
+}
+
+enum Step {
+    // The thread ran for its whole quantum.
+    Yield,
+    Fork(Thread),
+    Halt,
+}
+
+impl Thread {
+    fn new(tape: Tape) -> Thread {
+        Thread {
+            pc: 0,
+            tape,
+            procedures: Procedures::new(),
+            storage: 0,
+            halted: false,
+        }
+    }
+
+    // Runs at most `quantum` ops of `program`, stopping early at the end of the program or after
+    // a fork.
+    fn run(
+        &mut self,
+        program: &Brainfork,
+        quantum: usize,
+        input: &mut impl Iterator<Item = std::io::Result<u8>>,
+        write: &mut impl Write,
+        debug: &mut impl Write,
+    ) -> Result<Step> {
+        for _ in 0..quantum {
+            if self.pc == program.ops.len() {
+                return Ok(Step::Halt);
+            }
+            if let Op::End = program.ops[self.pc] {
+                return Ok(Step::Halt);
+            }
+            if let Op::Fork = program.ops[self.pc] {
+                self.pc += 1;
+                let mut child = self.clone();
+                self.tape.memory[self.tape.data_ptr] = 0;
+                let Tape { memory, data_ptr } = &mut child.tape;
+                move_ptr(data_ptr, 1, memory.len())?;
+                memory[*data_ptr] = 1;
+                return Ok(Step::Fork(child));
+            }
+            self.execute(program, input, write, debug)?;
+        }
+        Ok(Step::Yield)
+    }
+
+    // Runs the op at pc, which is not `Op::Fork` or `Op::End`.
+    fn execute(
+        &mut self,
+        program: &Brainfork,
+        input: &mut impl Iterator<Item = std::io::Result<u8>>,
+        write: &mut impl Write,
+        debug: &mut impl Write,
+    ) -> Result<()> {
+        let Tape { memory, data_ptr } = &mut self.tape;
+        let pc = &mut self.pc;
+        match program.ops[*pc] {
+            Op::IncPtr(n) => move_ptr(data_ptr, n as isize, memory.len())?,
+            Op::DecPtr(n) => move_ptr(data_ptr, -(n as isize), memory.len())?,
+            Op::IncData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_add(n as u8),
+            Op::DecData(n) => memory[*data_ptr] = memory[*data_ptr].wrapping_sub(n as u8),
+            Op::ReadStdin(n) => {
+                for _ in 0..n {
+                    read_cell(input, &mut memory[*data_ptr], program.eof)?;
+                }
+            }
+            Op::WriteStdout(n) => {
+                for _ in 0..n {
+                    write.write_all(&memory[*data_ptr..(*data_ptr + 1)])?;
+                }
+                write.flush()?;
+            }
+            Op::JumpIfDataIsZero(n) => {
+                if memory[*data_ptr] == 0 {
+                    *pc = n;
+                }
+            }
+            Op::JumpIfDataIsNotZero(n) => {
+                if memory[*data_ptr] != 0 {
+                    *pc = n;
+                }
+            }
+            Op::LoopSetToZero => memory[*data_ptr] = 0,
+            Op::LoopMovePtr(n) => scan_loop(memory, data_ptr, n)?,
+            Op::LoopMoveData(n) => {
+                if memory[*data_ptr] != 0 {
+                    let from = *data_ptr;
+                    if let Err(e) = move_ptr(data_ptr, n, memory.len()) {
+                        memory[from] = memory[from].wrapping_sub(1);
+                        return Err(e.into());
+                    }
+                    let to = std::mem::replace(data_ptr, from);
+                    memory[to] = memory[to].wrapping_add(memory[from]);
+                    memory[from] = 0;
+                }
+            }
+            Op::DebugDump(n) => dump_state(debug, n, *data_ptr, memory)?,
+            Op::DefineProcedure(end) => {
+                self.procedures.define(memory[*data_ptr], *pc);
+                *pc = end;
+            }
+            Op::EndProcedure => *pc = self.procedures.ret(),
+            Op::CallProcedure => *pc = self.procedures.call(memory[*data_ptr], *pc)?,
+            Op::Store => self.storage = memory[*data_ptr],
+            Op::Load => memory[*data_ptr] = self.storage,
+            ref op @ (Op::ShiftLeft(_)
+            | Op::ShiftRight(_)
+            | Op::Not
+            | Op::Xor
+            | Op::And
+            | Op::Or) => memory[*data_ptr] = apply_bitwise(op, memory[*data_ptr], self.storage),
+            Op::Fork | Op::End => unreachable!(),
+        }
+        *pc += 1;
+        Ok(())
+    }
+}
+
+// What the OS threads running a program share.
+struct Shared<I, W, D> {
+    input: Mutex<I>,
+    write: Mutex<W>,
+    debug: Mutex<D>,
+    // Number of threads which have not halted.
+    live: AtomicUsize,
+    // The first error of any thread, after which the others stop.
+    error: Mutex<Option<failure::Error>>,
+    failed: AtomicBool,
+}
+
+impl<I, W, D> Shared<I, W, D> {
+    fn fail(&self, e: failure::Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+// An input or output shared by the OS threads, locked for each byte.
+struct Locked<'a, T>(&'a Mutex<T>);
+
+impl<'a, I: Iterator> Iterator for Locked<'a, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.0.lock().unwrap().next()
+    }
+}
+
+impl<'a, W: Write> Write for Locked<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl Brainfork {
+    fn run_round_robin(
+        &self,
+        read: impl Read,
+        mut write: impl Write,
+        mut debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let mut input = input_bytes(read);
+        // The first thread is kept after it halts, for its tape.
+        let mut threads = vec![Thread::new(std::mem::replace(tape, Tape::new(0)))];
+        let mut live = 1;
+        let mut result = Ok(());
+        'run: while live > 0 {
+            let mut forked = Vec::new();
+            for thread in threads.iter_mut().filter(|thread| !thread.halted) {
+                match thread.run(self, 1, &mut input, &mut write, &mut debug) {
+                    Ok(Step::Yield) => (),
+                    Ok(Step::Fork(child)) => {
+                        live += 1;
+                        if live > MAX_THREADS {
+                            result = Err(RuntimeError::TooManyThreads.into());
+                            break 'run;
+                        }
+                        forked.push(child);
+                    }
+                    Ok(Step::Halt) => {
+                        thread.halted = true;
+                        live -= 1;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break 'run;
+                    }
+                }
+            }
+            if threads.len() > live + 1 {
+                let main = threads.remove(0);
+                threads.retain(|thread| !thread.halted);
+                threads.insert(0, main);
+            }
+            threads.extend(forked);
+        }
+        *tape = threads.swap_remove(0).tape;
+        result
+    }
+
+    // Runs the program with a thread of `Scheduling::OsThreads` per thread of the program. The
+    // first error of any thread is returned, and `tape` is left as the first thread left it.
+    pub fn run_threaded(
+        &self,
+        read: impl Read + Send,
+        write: impl Write + Send,
+        debug: impl Write + Send,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        let shared = Shared {
+            input: Mutex::new(input_bytes(read)),
+            write: Mutex::new(write),
+            debug: Mutex::new(debug),
+            live: AtomicUsize::new(1),
+            error: Mutex::new(None),
+            failed: AtomicBool::new(false),
+        };
+        let main = Thread::new(std::mem::replace(tape, Tape::new(0)));
+        let main = std::thread::scope(|scope| self.run_os_thread(scope, &shared, main));
+        *tape = main.tape;
+        match shared.error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Runs `thread` on the current OS thread, and its children on new ones, until it halts or
+    // any thread fails.
+    fn run_os_thread<'scope, 'env, I, W, D>(
+        &'env self,
+        scope: &'scope std::thread::Scope<'scope, 'env>,
+        shared: &'env Shared<I, W, D>,
+        mut thread: Thread,
+    ) -> Thread
+    where
+        I: Iterator<Item = std::io::Result<u8>> + Send,
+        W: Write + Send,
+        D: Write + Send,
+    {
+        let mut input = Locked(&shared.input);
+        let mut write = Locked(&shared.write);
+        let mut debug = Locked(&shared.debug);
+        while !shared.failed.load(Ordering::SeqCst) {
+            match thread.run(self, QUANTUM, &mut input, &mut write, &mut debug) {
+                Ok(Step::Yield) => (),
+                Ok(Step::Fork(child)) => {
+                    if shared.live.fetch_add(1, Ordering::SeqCst) >= MAX_THREADS {
+                        shared.live.fetch_sub(1, Ordering::SeqCst);
+                        shared.fail(RuntimeError::TooManyThreads.into());
+                        break;
+                    }
+                    scope.spawn(move || {
+                        self.run_os_thread(scope, shared, child);
+                    });
+                }
+                Ok(Step::Halt) => break,
+                Err(e) => {
+                    shared.fail(e);
+                    break;
+                }
+            }
+        }
+        shared.live.fetch_sub(1, Ordering::SeqCst);
+        thread
+    }
+}
+
+impl Brainfuck for Brainfork {
+    fn with_options(s: &[u8], options: &Options) -> Brainfork {
+        let instructions = filter_instructions(s, options);
+        Brainfork {
+            ops: translate_program(&instructions, true),
+            eof: options.eof,
+        }
+    }
+
+    // Runs the program with `Scheduling::RoundRobin`.
+    fn run_on_tape(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+    ) -> Result<()> {
+        self.run_round_robin(read, write, debug, tape)
+    }
+}
+
+// Runs `s`, which is in the dialect of `options`, like `Engine::run_on_tape`.
+pub fn run_on_tape<R: Read + Send, W: Write + Send, D: Write + Send>(
+    s: &[u8],
+    options: &Options,
+    scheduling: Scheduling,
+    r: R,
+    w: W,
+    d: D,
+    tape: &mut Tape,
+) -> Result<()> {
+    check_program_brackets(s, options)?;
+    let program = Brainfork::with_options(s, options);
+    match scheduling {
+        Scheduling::RoundRobin => program.run_on_tape(r, w, d, tape),
+        Scheduling::OsThreads => program.run_threaded(r, w, d, tape),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::dialect::Dialect;
+
+    fn options() -> Options {
+        Options {
+            dialect: Dialect::brainfork(),
+            ..Options::default()
+        }
+    }
+
+    fn run(s: &[u8], scheduling: Scheduling, tape: &mut Tape) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        run_on_tape(
+            s,
+            &options(),
+            scheduling,
+            &[] as &[u8],
+            &mut out,
+            std::io::sink(),
+            tape,
+        )?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_robin_test() {
+        // The child starts at a cell set to 1, and each thread runs one op a turn.
+        let mut tape = Tape::new(4);
+        assert_eq!(
+            run(b"Y++.+.", Scheduling::RoundRobin, &mut tape).unwrap(),
+            [2, 3, 3, 4]
+        );
+        assert_eq!(
+            tape,
+            Tape {
+                memory: vec![3, 0, 0, 0],
+                data_ptr: 0
+            }
+        );
+
+        // Every thread keeps forking, until too many run at once.
+        let e = run(b"+[Y+]", Scheduling::RoundRobin, &mut Tape::new(1024)).unwrap_err();
+        assert_eq!(error_kind(&e), "too-many-threads");
+        let e = run(b"Y>", Scheduling::RoundRobin, &mut Tape::new(2)).unwrap_err();
+        assert_eq!(error_kind(&e), "pointer-out-of-bounds");
+        assert!(Engine::Interpreter3.compile(b"Y", &options()).is_err());
+    }
+
+    #[test]
+    fn os_threads_test() {
+        let mut tape = Tape::new(4);
+        let mut out = run(b"Y++.+.", Scheduling::OsThreads, &mut tape).unwrap();
+        out.sort();
+        assert_eq!(out, [2, 3, 3, 4]);
+        assert_eq!(tape.memory, vec![3, 0, 0, 0]);
+
+        let e = run(b"+[Y+]", Scheduling::OsThreads, &mut Tape::new(1024)).unwrap_err();
+        assert_eq!(error_kind(&e), "too-many-threads");
+    }
+}
@@ -0,0 +1,282 @@
+// Formatting of brainfuck programs.
+//
+// Programs are parsed losslessly into instructions, comments and whitespace, so that comments keep
+// their place between instructions. Loops are indented by their nesting, except short ones without
+// a comment or loop inside, like `[->+<]`, which stay on one line. Code is wrapped at `WIDTH`
+// columns, long runs of one instruction are split into groups of `GROUP`, and each comment is kept
+// either on a line of its own or at the end of the line of code it followed.
+use crate::brainfuck::*;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+pub const WIDTH: usize = 80;
+const INDENT: usize = 2;
+const GROUP: usize = 10;
+// Longest loop, in instructions, which is kept on one line.
+const MAX_INLINE_LOOP: usize = 32;
+
+const INSTRUCTIONS: &[u8] = b"+-<>[],.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // A single instruction.
+    Instruction,
+    // Any other text, without a newline or whitespace at either end.
+    Comment,
+    Whitespace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    // Offset of the token in the program.
+    pub offset: usize,
+    pub text: &'a [u8],
+}
+
+// The tokens of `s`, whose texts add up to `s`.
+pub fn parse(s: &[u8]) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while offset < s.len() {
+        let rest = &s[offset..];
+        let (kind, len) = if INSTRUCTIONS.contains(&rest[0]) {
+            (TokenKind::Instruction, 1)
+        } else if rest[0].is_ascii_whitespace() {
+            let len = rest
+                .iter()
+                .position(|c| !c.is_ascii_whitespace())
+                .unwrap_or(rest.len());
+            (TokenKind::Whitespace, len)
+        } else {
+            let end = rest
+                .iter()
+                .position(|c| INSTRUCTIONS.contains(c) || *c == b'\n')
+                .unwrap_or(rest.len());
+            let len = rest[..end]
+                .iter()
+                .rposition(|c| !c.is_ascii_whitespace())
+                .unwrap()
+                + 1;
+            (TokenKind::Comment, len)
+        };
+        tokens.push(Token {
+            kind,
+            offset,
+            text: &rest[..len],
+        });
+        offset += len;
+    }
+    tokens
+}
+
+// Lines of formatted code, being written.
+struct Lines {
+    out: Vec<u8>,
+    line: Vec<u8>,
+    depth: usize,
+    // Whether a blank line goes before the next line.
+    blank: bool,
+}
+
+impl Lines {
+    // Appends `text` to the current line, after a space if `spaced`, or to a new line if it would
+    // not fit.
+    fn push(&mut self, text: &[u8], spaced: bool) {
+        let indent = self.depth * INDENT;
+        if !self.line.is_empty() && indent + self.line.len() + 1 + text.len() > WIDTH {
+            self.end_line();
+        }
+        if !self.line.is_empty() && spaced {
+            self.line.push(b' ');
+        }
+        self.line.extend_from_slice(text);
+    }
+
+    fn end_line(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        if self.blank && !self.out.is_empty() {
+            self.out.push(b'\n');
+        }
+        self.blank = false;
+        self.out
+            .extend(std::iter::repeat_n(b' ', self.depth * INDENT));
+        self.out.append(&mut self.line);
+        self.out.push(b'\n');
+    }
+
+    // Appends a run of `n` instructions `c`.
+    fn push_run(&mut self, c: u8, n: usize) {
+        for i in (0..n).step_by(GROUP) {
+            let group = vec![c; std::cmp::min(GROUP, n - i)];
+            self.push(&group, i > 0);
+        }
+    }
+}
+
+// `s`, formatted.
+pub fn format(s: &[u8]) -> Result<Vec<u8>> {
+    let tokens = parse(s);
+    let (instructions, positions): (Vec<u8>, Vec<usize>) = tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Instruction)
+        .map(|token| (token.text[0], token.offset))
+        .unzip();
+    check_brackets(&instructions).map_err(|e| e.at(&positions))?;
+
+    let mut lines = Lines {
+        out: Vec::new(),
+        line: Vec::new(),
+        depth: 0,
+        blank: false,
+    };
+    // Whether the last token which is not whitespace is on the current line of `s`.
+    let mut same_line = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        match token.kind {
+            TokenKind::Whitespace => {
+                let newlines = token.text.iter().filter(|c| **c == b'\n').count();
+                if newlines > 0 {
+                    same_line = false;
+                }
+                if newlines > 1 {
+                    lines.end_line();
+                    lines.blank = true;
+                }
+                i += 1;
+                continue;
+            }
+            TokenKind::Comment => {
+                if !same_line {
+                    lines.end_line();
+                }
+                lines.push(token.text, true);
+                lines.end_line();
+                i += 1;
+            }
+            TokenKind::Instruction => match token.text[0] {
+                b'[' => match inline_loop(&tokens[i..]) {
+                    Some((text, len)) => {
+                        lines.push(&text, false);
+                        i += len;
+                    }
+                    None => {
+                        lines.push(b"[", false);
+                        lines.end_line();
+                        lines.depth += 1;
+                        i += 1;
+                    }
+                },
+                b']' => {
+                    lines.end_line();
+                    lines.depth -= 1;
+                    lines.push(b"]", false);
+                    i += 1;
+                }
+                c => {
+                    // A run goes on over whitespace, but not comments or blank lines.
+                    let mut n = 1;
+                    i += 1;
+                    while i < tokens.len() {
+                        let token = &tokens[i];
+                        if token.kind == TokenKind::Instruction && token.text[0] == c {
+                            n += 1;
+                            i += 1;
+                        } else if token.kind == TokenKind::Whitespace
+                            && token.text.iter().filter(|c| **c == b'\n').count() < 2
+                            && tokens.get(i + 1).map(|t| t.text) == Some(&[c][..])
+                        {
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    lines.push_run(c, n);
+                }
+            },
+        }
+        same_line = true;
+    }
+    lines.end_line();
+    Ok(lines.out)
+}
+
+// The loop starting at `tokens[0]` and the number of its tokens, if it goes on one line.
+fn inline_loop(tokens: &[Token<'_>]) -> Option<(Vec<u8>, usize)> {
+    let mut text = vec![b'['];
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        match token.kind {
+            TokenKind::Whitespace => {}
+            TokenKind::Comment => return None,
+            TokenKind::Instruction => match token.text[0] {
+                b'[' => return None,
+                b']' => {
+                    text.push(b']');
+                    return Some((text, i + 1));
+                }
+                c if text.len() < MAX_INLINE_LOOP + 1 => text.push(c),
+                _ => return None,
+            },
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn format_test() {
+        let s = b"++  +++++ +++++ ++ set up\n\n\n[>+++[-]<- loop\n# count\n ]>. [.]\n";
+        assert_eq!(
+            String::from_utf8(format(s).unwrap()).unwrap(),
+            "++++++++++ ++++ set up\n\n[\n  >+++[-]<- loop\n  # count\n]>.[.]\n"
+        );
+        let tokens = parse(b"+a b \n");
+        assert_eq!(
+            tokens.iter().map(|t| t.kind).collect::<Vec<_>>(),
+            [
+                TokenKind::Instruction,
+                TokenKind::Comment,
+                TokenKind::Whitespace
+            ]
+        );
+        assert_eq!(tokens[1].text, b"a b");
+        assert_eq!(
+            format(b"+]").err().unwrap().downcast_ref(),
+            Some(&SyntaxError::UnmatchedCloseBracket(1))
+        );
+    }
+
+    // Formatting keeps the instructions and comments of every program, and is idempotent.
+    #[test]
+    fn corpus_test() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let mut count = 0;
+        for dir in &["src/testcases", "benches/brainfuck"] {
+            for path in glob::glob(&format!("{}/{}/*.bf", root, dir)).unwrap() {
+                let path = path.unwrap();
+                let s = std::fs::read(&path).unwrap();
+                let formatted = format(&s).unwrap();
+                let parsed = parse(&s);
+                assert_eq!(parsed.iter().flat_map(|t| t.text).count(), s.len());
+                let non_whitespace = |s: &[u8]| -> Vec<u8> {
+                    s.iter()
+                        .cloned()
+                        .filter(|c| !c.is_ascii_whitespace())
+                        .collect()
+                };
+                assert_eq!(non_whitespace(&formatted), non_whitespace(&s), "{:?}", path);
+                assert_eq!(format(&formatted).unwrap(), formatted, "{:?}", path);
+                count += 1;
+            }
+        }
+        assert!(count > 10);
+    }
+}
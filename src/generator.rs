@@ -0,0 +1,285 @@
+// Random brainfuck programs, to test the engines against each other.
+//
+// Generated programs always terminate and never leave the tape. Each loop either counts down a
+// cell reserved for its nesting depth, which nothing else touches, or is one of the idioms
+// recognized by `optimize_loop`. The data pointer is known statically everywhere, except after
+// an optional scan loop which ends the program.
+use crate::brainfuck::*;
+use crate::differential;
+use std::time::Duration;
+
+// xorshift64*. Good enough for generating programs, and reproducible everywhere.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The state must not be zero.
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    // Seeds from the FNV-1a hash of `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Rng {
+        let hash = bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash: u64, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01B3)
+        });
+        Rng::new(hash)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // A number in 0..n.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn percent(&mut self, p: u32) -> bool {
+        self.below(100) < p as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    // The number of statements at the top level.
+    pub statements: usize,
+    pub max_depth: usize,
+    // The chance, in percent, that a statement is a counted loop.
+    pub loop_density: u32,
+    // The upper bound of the counter of each counted loop.
+    pub max_iterations: usize,
+    pub data_cells: usize,
+    // Whether to use `,` and `.`.
+    pub io: bool,
+    // Whether the program may end with `[>]`, `[>>]` or `[<]`.
+    pub scan_loops: bool,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> GeneratorConfig {
+        GeneratorConfig {
+            statements: 20,
+            max_depth: 3,
+            loop_density: 20,
+            max_iterations: 8,
+            data_cells: 6,
+            io: true,
+            scan_loops: true,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    pub fn random(rng: &mut Rng) -> GeneratorConfig {
+        GeneratorConfig {
+            statements: 1 + rng.below(30),
+            max_depth: rng.below(4),
+            loop_density: rng.below(60) as u32,
+            max_iterations: 1 + rng.below(10),
+            data_cells: 1 + rng.below(8),
+            io: rng.percent(80),
+            scan_loops: rng.percent(50),
+        }
+    }
+}
+
+struct Generator<'a> {
+    rng: &'a mut Rng,
+    config: &'a GeneratorConfig,
+    program: Vec<u8>,
+    data_ptr: usize,
+    // Cells below `data_start` are the loop counters, one per depth. They are zero outside their
+    // loops, so that `[<]` stops on them. Without loops, cell 0 is left zero for the same reason.
+    data_start: usize,
+}
+
+impl<'a> Generator<'a> {
+    fn emit(&mut self, c: u8, n: usize) {
+        self.program.extend(std::iter::repeat_n(c, n));
+    }
+
+    fn move_to(&mut self, cell: usize) {
+        if cell > self.data_ptr {
+            self.emit(b'>', cell - self.data_ptr);
+        } else {
+            self.emit(b'<', self.data_ptr - cell);
+        }
+        self.data_ptr = cell;
+    }
+
+    fn random_data_cell(&mut self) -> usize {
+        self.data_start + self.rng.below(self.config.data_cells)
+    }
+
+    // A statement starts and ends with the data pointer on a data cell.
+    fn statement(&mut self, depth: usize) {
+        if depth < self.config.max_depth && self.rng.percent(self.config.loop_density) {
+            self.counted_loop(depth);
+            return;
+        }
+        match self.rng.below(9) {
+            0 | 1 => {
+                let cell = self.random_data_cell();
+                self.move_to(cell);
+            }
+            2 | 3 => {
+                let n = 1 + self.rng.below(16);
+                self.emit(b'+', n);
+            }
+            4 => {
+                let n = 1 + self.rng.below(16);
+                self.emit(b'-', n);
+            }
+            5 => {
+                if self.rng.percent(50) {
+                    self.program.extend(b"[-]");
+                } else {
+                    self.program.extend(b"[+]");
+                }
+            }
+            6 => self.move_data_loop(),
+            7 if self.config.io => self.program.push(b'.'),
+            8 if self.config.io && self.rng.percent(30) => self.program.push(b','),
+            _ => (),
+        }
+    }
+
+    // `[->+<]` and its variants, some of which `optimize_loop` does not recognize.
+    fn move_data_loop(&mut self) {
+        let from = self.data_ptr;
+        let to = self.random_data_cell();
+        if from == to {
+            return;
+        }
+        let decrement_first = self.rng.percent(70);
+        let increments = if self.rng.percent(70) { 1 } else { 2 };
+        self.program.push(b'[');
+        if decrement_first {
+            self.program.push(b'-');
+        }
+        self.move_to(to);
+        self.emit(b'+', increments);
+        self.move_to(from);
+        if !decrement_first {
+            self.program.push(b'-');
+        }
+        self.program.push(b']');
+    }
+
+    fn counted_loop(&mut self, depth: usize) {
+        let from = self.data_ptr;
+        let counter = depth;
+        self.move_to(counter);
+        let n = 1 + self.rng.below(self.config.max_iterations);
+        self.emit(b'+', n);
+        self.program.push(b'[');
+        let cell = self.random_data_cell();
+        self.move_to(cell);
+        for _ in 0..1 + self.rng.below(4) {
+            self.statement(depth + 1);
+        }
+        self.move_to(counter);
+        self.program.extend(b"-]");
+        self.move_to(from);
+    }
+
+    fn scan_loop(&mut self) {
+        match self.rng.below(3) {
+            0 => self.program.extend(b"[>]"),
+            1 => self.program.extend(b"[>>]"),
+            _ => self.program.extend(b"[<]"),
+        }
+        self.program.extend(b"+");
+        if self.config.io {
+            self.program.push(b'.');
+        }
+    }
+}
+
+pub fn generate(rng: &mut Rng, config: &GeneratorConfig) -> Vec<u8> {
+    assert!(config.data_cells > 0);
+    let mut generator = Generator {
+        rng,
+        config,
+        program: Vec::new(),
+        data_ptr: 0,
+        data_start: std::cmp::max(config.max_depth, 1),
+    };
+    let start = generator.data_start;
+    generator.move_to(start);
+    for _ in 0..config.statements {
+        generator.statement(0);
+    }
+    if config.scan_loops && generator.rng.percent(50) {
+        generator.scan_loop();
+    }
+    generator.program
+}
+
+// How long each engine may run `data` itself as a program in `fuzz_engines`.
+const FUZZ_BUDGET: Duration = Duration::from_millis(100);
+
+fn check_divergence(report: &differential::DifferentialReport, program: &[u8], input: &[u8]) {
+    if let Some(divergence) = &report.divergence {
+        panic!(
+            "{}\nprogram: {}\ninput: {:?}",
+            divergence,
+            String::from_utf8_lossy(program),
+            input
+        );
+    }
+}
+
+// The entry point for fuzzers. `data` is used as the seed of a generated program and its input,
+// which are run on every engine. `data` itself is run with the same input too, but since it may
+// never end, only for `FUZZ_BUDGET`. Panics if the engines diverge.
+pub fn fuzz_engines(data: &[u8]) {
+    let options = Options::default();
+    let mut rng = Rng::from_bytes(data);
+    let config = GeneratorConfig::random(&mut rng);
+    let program = generate(&mut rng, &config);
+    let input: Vec<u8> = (0..rng.below(8)).map(|_| rng.next_u64() as u8).collect();
+    let report = differential::differential_run(&program, &input, &options);
+    check_divergence(&report, &program, &input);
+    match report.outcomes[0].1.error.as_deref() {
+        None | Some("input-unavailable") => (),
+        Some(e) => panic!(
+            "generated program failed: {}\nprogram: {}",
+            e,
+            String::from_utf8_lossy(&program)
+        ),
+    }
+
+    if let Some(report) = differential::differential_run_within(data, &input, &options, FUZZ_BUDGET)
+    {
+        check_divergence(&report, data, &input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let config = GeneratorConfig::default();
+        let program = generate(&mut Rng::new(1), &config);
+        assert_eq!(program, generate(&mut Rng::new(1), &config));
+        assert_eq!(check_brackets(&program), Ok(()));
+    }
+
+    #[test]
+    fn fuzz_engines_test() {
+        for seed in 0..300u32 {
+            fuzz_engines(&seed.to_le_bytes());
+        }
+        fuzz_engines(b"+[>+<-]]");
+        fuzz_engines(b"+[]");
+        fuzz_engines(b"+[>+]");
+        fuzz_engines(b"<,[.,]");
+    }
+}
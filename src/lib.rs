@@ -1,3 +1,22 @@
+pub mod analysis;
+pub mod bench;
+pub mod bfm;
+pub mod bit;
 mod brainfuck;
+mod closure;
+pub mod coverage;
+pub mod dialect;
+pub mod differential;
+pub mod fork;
+pub mod format;
+pub mod generator;
+pub mod lint;
+pub mod minify;
+pub mod prefix;
+pub mod profile;
+mod scan;
+pub mod testcase;
+pub mod text;
+pub mod trace;
 
 pub use crate::brainfuck::*;
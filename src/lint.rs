@@ -0,0 +1,330 @@
+// Warnings about likely mistakes in brainfuck programs, found without running them.
+use crate::format::{self, TokenKind};
+use crate::profile;
+use serde_derive::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    // Such as "dead-loop".
+    pub kind: &'static str,
+    // 1-based line and column, in bytes, of the instruction warned about.
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} [{}]",
+            self.line, self.column, self.message, self.kind
+        )
+    }
+}
+
+struct Linter {
+    lines: profile::LineIndex,
+    instructions: Vec<u8>,
+    positions: Vec<usize>,
+    warnings: Vec<Warning>,
+}
+
+impl Linter {
+    // Warns about the instruction at `pc`.
+    fn warn(&mut self, pc: usize, kind: &'static str, message: String) {
+        let (line, column) = self.lines.line_column(self.positions[pc]);
+        self.warnings.push(Warning {
+            kind,
+            line,
+            column,
+            message,
+        });
+    }
+
+    // The pc of the `]` closing each `[`, warning about the brackets which are unmatched.
+    fn match_brackets(&mut self) -> Vec<Option<usize>> {
+        let mut ends = vec![None; self.instructions.len()];
+        let mut open = Vec::new();
+        for pc in 0..self.instructions.len() {
+            match self.instructions[pc] {
+                b'[' => open.push(pc),
+                b']' => match open.pop() {
+                    Some(start) => ends[start] = Some(pc),
+                    None => self.warn(pc, "unmatched-bracket", "unmatched `]`".to_string()),
+                },
+                _ => {}
+            }
+        }
+        for pc in open {
+            self.warn(pc, "unmatched-bracket", "unmatched `[`".to_string());
+        }
+        ends
+    }
+
+    fn cancelling_pairs(&mut self) {
+        let mut pc = 1;
+        while pc < self.instructions.len() {
+            let pair = &self.instructions[pc - 1..=pc];
+            if [&b"+-"[..], b"-+", b"<>", b"><"].contains(&pair) {
+                let message = format!("`{}` cancels out", String::from_utf8_lossy(pair));
+                self.warn(pc - 1, "cancelling-instructions", message);
+                pc += 1;
+            }
+            pc += 1;
+        }
+    }
+
+    // Loops right after a `]`, where the cell is zero.
+    fn dead_loops(&mut self) {
+        for pc in 1..self.instructions.len() {
+            if &self.instructions[pc - 1..=pc] == b"][" {
+                let message = "loop never runs, right after another loop".to_string();
+                self.warn(pc, "dead-loop", message);
+            }
+        }
+    }
+
+    // Loops which change no cell and come back to the same cell, like `[]` and `[.]`, entered on a
+    // cell known to be nonzero, so that they never end.
+    fn infinite_loops(&mut self, ends: &[Option<usize>]) {
+        let mut cells = KnownCells {
+            values: HashMap::new(),
+            rest: Some(0),
+            data_ptr: 0,
+        };
+        // For each loop around `pc`, the offsets of the cells it changes, or `None` if it does not
+        // come back to the cell it started from.
+        let mut changes: Vec<Option<Vec<isize>>> = Vec::new();
+        let mut pc = 0;
+        while pc < self.instructions.len() {
+            let data_ptr = cells.data_ptr;
+            match self.instructions[pc] {
+                b'>' => cells.data_ptr += 1,
+                b'<' => cells.data_ptr -= 1,
+                b'+' => cells.set(data_ptr, cells.get(data_ptr).map(|v| v.wrapping_add(1))),
+                b'-' => cells.set(data_ptr, cells.get(data_ptr).map(|v| v.wrapping_sub(1))),
+                b',' => cells.set(data_ptr, None),
+                b'[' => {
+                    let value = cells.get(data_ptr);
+                    match ends[pc] {
+                        // The loop never runs.
+                        Some(end) if value == Some(0) => {
+                            pc = end + 1;
+                            continue;
+                        }
+                        None if value == Some(0) => return,
+                        Some(end) if is_balanced(&self.instructions[pc..=end]) => {
+                            // The cells the loop changes are unknown from its second iteration
+                            // on.
+                            let offsets = changed_cells(&self.instructions[pc + 1..end]);
+                            if value.is_some() && offsets.is_empty() {
+                                let message = "loop runs forever".to_string();
+                                self.warn(pc, "infinite-loop", message);
+                            }
+                            for offset in &offsets {
+                                cells.set(data_ptr + offset, None);
+                            }
+                            changes.push(Some(offsets));
+                        }
+                        _ => {
+                            cells.forget();
+                            changes.push(None);
+                        }
+                    }
+                }
+                b']' => {
+                    // The loop may have run any number of times.
+                    match changes.pop() {
+                        Some(Some(offsets)) => {
+                            for offset in offsets {
+                                cells.set(data_ptr + offset, None);
+                            }
+                        }
+                        _ => cells.forget(),
+                    }
+                    cells.set(cells.data_ptr, Some(0));
+                }
+                _ => {}
+            }
+            pc += 1;
+        }
+    }
+
+    // The first `<` which moves left of cell 0 on every path, if any. Only loops which come back
+    // to the cell they started from are followed.
+    fn pointer_underflow(&mut self, ends: &[Option<usize>]) {
+        let mut data_ptr = 0;
+        let mut pc = 0;
+        while pc < self.instructions.len() {
+            match self.instructions[pc] {
+                b'>' => data_ptr += 1,
+                b'<' if data_ptr == 0 => {
+                    let message = "moves left of cell 0".to_string();
+                    self.warn(pc, "pointer-underflow", message);
+                    return;
+                }
+                b'<' => data_ptr -= 1,
+                b'[' => match ends[pc] {
+                    Some(end) if is_balanced(&self.instructions[pc..=end]) => pc = end,
+                    _ => return,
+                },
+                _ => {}
+            }
+            pc += 1;
+        }
+    }
+
+    // Instructions written right after a word, like the `.` and `,` of prose in a comment.
+    fn instructions_in_comments(&mut self, tokens: &[format::Token<'_>]) {
+        let mut pc = 0;
+        let mut previous: Option<&format::Token<'_>> = None;
+        for token in tokens {
+            if token.kind == TokenKind::Instruction {
+                if let Some(previous) = previous {
+                    if previous.kind == TokenKind::Comment
+                        && previous.text.last().unwrap().is_ascii_alphanumeric()
+                    {
+                        let message = format!(
+                            "`{}` right after comment text is an instruction",
+                            token.text[0] as char
+                        );
+                        self.warn(pc, "instruction-in-comment", message);
+                    }
+                }
+                pc += 1;
+            }
+            previous = Some(token);
+        }
+    }
+}
+
+// What is known of the cells at a point of a program, without running it.
+struct KnownCells {
+    // Cells by their offset from `data_ptr`'s origin, which are known to be a value or not.
+    values: HashMap<isize, Option<u8>>,
+    // The value of the other cells: zero at the start of a program, and unknown once the pointer
+    // has moved by an unknown amount.
+    rest: Option<u8>,
+    data_ptr: isize,
+}
+
+impl KnownCells {
+    fn get(&self, offset: isize) -> Option<u8> {
+        self.values.get(&offset).cloned().unwrap_or(self.rest)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<u8>) {
+        self.values.insert(offset, value);
+    }
+
+    fn forget(&mut self) {
+        self.values.clear();
+        self.rest = None;
+    }
+}
+
+// The offsets of the cells `s` changes, from the cell it starts on. Every loop in `s` must come
+// back to the cell it started from.
+fn changed_cells(s: &[u8]) -> Vec<isize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for c in s {
+        match c {
+            b'>' => offset += 1,
+            b'<' => offset -= 1,
+            b'+' | b'-' | b',' => offsets.push(offset),
+            _ => {}
+        }
+    }
+    offsets
+}
+
+// Whether every loop in `s`, whose brackets are matched, comes back to the cell it started from.
+fn is_balanced(s: &[u8]) -> bool {
+    let mut moves = vec![0];
+    for c in s {
+        match c {
+            b'[' => moves.push(0),
+            b']' if moves.pop() != Some(0) => return false,
+            b'>' => *moves.last_mut().unwrap() += 1,
+            b'<' => *moves.last_mut().unwrap() -= 1,
+            _ => {}
+        }
+    }
+    true
+}
+
+// The warnings about `s`, in the order of their positions.
+pub fn lint(s: &[u8]) -> Vec<Warning> {
+    let tokens = format::parse(s);
+    let (instructions, positions) = tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Instruction)
+        .map(|token| (token.text[0], token.offset))
+        .unzip();
+    let mut linter = Linter {
+        lines: profile::LineIndex::new(s),
+        instructions,
+        positions,
+        warnings: Vec::new(),
+    };
+    let ends = linter.match_brackets();
+    linter.cancelling_pairs();
+    linter.dead_loops();
+    linter.infinite_loops(&ends);
+    linter.pointer_underflow(&ends);
+    linter.instructions_in_comments(&tokens);
+
+    linter
+        .warnings
+        .sort_by_key(|warning| (warning.line, warning.column));
+    linter.warnings
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn lint_test() {
+        let s = b"Hello, world\n+-[-][-]<\n+[.]>>[<<<]\n]";
+        let warnings: Vec<_> = lint(s)
+            .into_iter()
+            .map(|w| (w.kind, w.line, w.column))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                ("instruction-in-comment", 1, 6),
+                ("cancelling-instructions", 2, 1),
+                ("dead-loop", 2, 6),
+                ("pointer-underflow", 2, 9),
+                ("infinite-loop", 3, 2),
+                ("unmatched-bracket", 4, 1),
+            ]
+        );
+        assert_eq!(
+            lint(b"+-")[0].to_string(),
+            "1:1: `+-` cancels out [cancelling-instructions]"
+        );
+        assert!(lint(b">[-]<[->+<]>[<]").is_empty());
+        // Comments in a loop which never runs.
+        assert!(lint(b"[ see [.] ]+[-]").is_empty());
+        // Loops on a cell known to be zero never run, even once another cell has changed.
+        assert!(lint(b"+>[.]<").is_empty());
+        assert!(lint(b"+[-]>[.]").is_empty());
+        // Loops on a cell which may be zero may end.
+        assert!(lint(b",[.]").is_empty());
+        assert!(lint(b"+[->+<]>[.]").is_empty());
+        assert_eq!(
+            lint(b"+[>+<-]>[<]+[.]")
+                .into_iter()
+                .map(|w| (w.kind, w.column))
+                .collect::<Vec<_>>(),
+            [("infinite-loop", 13)]
+        );
+    }
+}
@@ -1,5 +1,5 @@
-use brainfuck;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 type Result<T> = std::result::Result<T, failure::Error>;
@@ -16,7 +16,7 @@ struct Opt {
     #[structopt(
         short = "o",
         long = "optimize",
-        help = "Optimization level (1-3)",
+        help = "Optimization level (1-4)",
         conflicts_with = "jit"
     )]
     optimize: Option<u64>,
@@ -26,17 +26,577 @@ struct Opt {
         help = "Use JIT (Just-in-time) compilation (linux x86-64 only)"
     )]
     jit: bool,
-    program: String,
+    #[structopt(
+        long = "debug-hash",
+        help = "Dump the machine state to stderr at each `#` instruction"
+    )]
+    debug_hash: bool,
+    #[structopt(
+        long = "dialect",
+        help = "Language of programs: brainfuck, ook, pbrain, brainfork, extended-type-1, or a JSON dialect file. By default, from the extension of <program>"
+    )]
+    dialect: Option<brainfuck::dialect::Dialect>,
+    #[structopt(
+        long = "eof",
+        help = "What `,` does at the end of input: error, zero, minus-one or unchanged",
+        default_value = "error"
+    )]
+    eof: brainfuck::EofPolicy,
+    #[structopt(
+        long = "partial-eval",
+        help = "Run the part of <program> before its first input when it is compiled"
+    )]
+    partial_eval: bool,
+    #[structopt(
+        long = "bit-language",
+        help = "Run <program> as a language with 1-bit cells: boolfuck or smallfuck",
+        raw(conflicts_with_all = r#"&["jit", "optimize", "dialect"]"#)
+    )]
+    bit_language: Option<brainfuck::bit::Language>,
+    #[structopt(
+        long = "os-threads",
+        help = "Run each thread of a brainfork program on an OS thread, instead of taking turns deterministically"
+    )]
+    os_threads: bool,
+    #[structopt(
+        long = "profile",
+        help = "Print an execution profile to stderr",
+        conflicts_with = "jit"
+    )]
+    profile: bool,
+    #[structopt(
+        long = "profile-top",
+        help = "Number of hot loops shown by --profile",
+        default_value = "10"
+    )]
+    profile_top: usize,
+    #[structopt(
+        long = "coverage",
+        help = "Write lcov coverage to <coverage>. <program> may be a directory of .bf and .test files",
+        parse(from_os_str),
+        conflicts_with = "jit"
+    )]
+    coverage: Option<PathBuf>,
+    #[structopt(
+        long = "trace",
+        help = "Write a Chrome trace of loops and I/O to <trace>",
+        parse(from_os_str),
+        conflicts_with = "jit"
+    )]
+    trace: Option<PathBuf>,
+    program: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "analyze",
+        about = "Reports the cells <program> may reach, and the bounds checks and loop counters known without running it"
+    )]
+    Analyze {
+        #[structopt(
+            long = "tape-size",
+            help = "Number of cells of the tape bounds checks are against",
+            default_value = "30000"
+        )]
+        tape_size: usize,
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+    },
+    #[structopt(
+        name = "bench",
+        about = "Benchmarks the engines on every .bf program in a directory"
+    )]
+    Bench {
+        #[structopt(
+            long = "engine",
+            help = "Engine to benchmark, which may be repeated. Every engine by default",
+            raw(number_of_values = "1")
+        )]
+        engines: Vec<brainfuck::Engine>,
+        #[structopt(
+            long = "iterations",
+            help = "Number of runs of each program, of which the fastest is shown",
+            default_value = "3"
+        )]
+        iterations: usize,
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "build",
+        about = "Compiles a .bfm program to brainfuck, and writes it to stdout"
+    )]
+    Build {
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+    },
+    #[structopt(
+        name = "fmt",
+        about = "Formats programs in place, or stdin to stdout if no program is given"
+    )]
+    Fmt {
+        #[structopt(
+            long = "check",
+            help = "Only list the programs which are not formatted, and fail if there are any"
+        )]
+        check: bool,
+        #[structopt(parse(from_os_str))]
+        programs: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "gen-print",
+        about = "Writes a brainfuck program which prints <text> to stdout, and its length to stderr"
+    )]
+    GenPrint {
+        #[structopt(
+            long = "strategy",
+            help = "shortest, for the fewest instructions, or fastest, for the fewest instructions run",
+            default_value = "shortest"
+        )]
+        strategy: brainfuck::text::Strategy,
+        #[structopt(help = "Text to print. Read from stdin if omitted")]
+        text: Option<String>,
+    },
+    #[structopt(
+        name = "lint",
+        about = "Warns about likely mistakes in programs, and fails if there are any"
+    )]
+    Lint {
+        #[structopt(long = "json", help = "Print the warnings as a JSON array")]
+        json: bool,
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        programs: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "minify",
+        about = "Writes <program> without comments and with fewer instructions to stdout, and its length to stderr"
+    )]
+    Minify {
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+    },
+    #[structopt(
+        name = "translate",
+        about = "Translates a program between dialects, and writes it to stdout"
+    )]
+    Translate {
+        #[structopt(
+            long = "from",
+            help = "Dialect of <program>: brainfuck, ook, pbrain, brainfork, extended-type-1, or a JSON dialect file. By default, from its extension"
+        )]
+        from: Option<brainfuck::dialect::Dialect>,
+        #[structopt(long = "to", help = "Dialect to translate to")]
+        to: brainfuck::dialect::Dialect,
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+    },
+    #[structopt(
+        name = "diff-engines",
+        about = "Runs every .bf program in a directory on every engine and compares the results"
+    )]
+    DiffEngines {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "test",
+        about = "Runs the test cases in a directory: .bf programs with their expectations in .test files"
+    )]
+    Test {
+        #[structopt(
+            long = "engine",
+            help = "Engine to run the tests on",
+            default_value = "interpreter1"
+        )]
+        engine: brainfuck::Engine,
+        #[structopt(
+            long = "check",
+            help = "Run the tests on every engine",
+            conflicts_with = "engine"
+        )]
+        check: bool,
+        #[structopt(
+            long = "bless",
+            help = "Record the output of each program on interpreter1 in its .test file, creating it if needed",
+            raw(conflicts_with_all = r#"&["engine", "check"]"#)
+        )]
+        bless: bool,
+        #[structopt(
+            long = "timeout",
+            help = "Timeout of each test in seconds",
+            default_value = "10"
+        )]
+        timeout: u64,
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+fn run_command(command: Command, options: &brainfuck::Options) -> Result<()> {
+    match command {
+        Command::Analyze { tape_size, program } => {
+            let report =
+                brainfuck::analysis::analyze(&std::fs::read(&program)?, options, tape_size)?;
+            match report.cells {
+                Some(cells) => println!("cells: {}", cells),
+                None => println!("cells: unbounded"),
+            }
+            println!(
+                "moves which need no bounds check: {} of {}",
+                report.moves_in_bounds, report.moves
+            );
+            println!(
+                "loops with a known counter: {} of {}",
+                report.constant_loops, report.loops
+            );
+            Ok(())
+        }
+        Command::Build { program } => {
+            let s = brainfuck::bfm::compile(&std::fs::read(&program)?)
+                .map_err(|e| failure::format_err!("{}: {}", program.display(), e))?;
+            std::io::stdout().write_all(&s)?;
+            Ok(())
+        }
+        Command::Fmt { check, programs } => {
+            if programs.is_empty() {
+                let mut s = Vec::new();
+                std::io::stdin().read_to_end(&mut s)?;
+                let formatted = brainfuck::format::format(&s)?;
+                if check {
+                    if formatted != s {
+                        return Err(failure::format_err!("stdin is not formatted"));
+                    }
+                } else {
+                    std::io::stdout().write_all(&formatted)?;
+                }
+                return Ok(());
+            }
+            let mut unformatted = 0;
+            for program in &programs {
+                let s = std::fs::read(program)?;
+                let formatted = brainfuck::format::format(&s)
+                    .map_err(|e| failure::format_err!("{}: {}", program.display(), e))?;
+                if formatted == s {
+                    continue;
+                }
+                unformatted += 1;
+                if check {
+                    println!("{}", program.display());
+                } else {
+                    std::fs::write(program, &formatted)?;
+                }
+            }
+            if check && unformatted > 0 {
+                return Err(failure::format_err!(
+                    "{} of {} programs are not formatted",
+                    unformatted,
+                    programs.len()
+                ));
+            }
+            Ok(())
+        }
+        Command::GenPrint { strategy, text } => {
+            let text = match text {
+                Some(text) => text.into_bytes(),
+                None => {
+                    let mut text = Vec::new();
+                    std::io::stdin().read_to_end(&mut text)?;
+                    text
+                }
+            };
+            let program = brainfuck::text::generate(&text, strategy);
+            let naive = brainfuck::text::naive(&text).len();
+            eprintln!(
+                "{} instructions, against {} printing with + alone",
+                program.len(),
+                naive
+            );
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&program)?;
+            writeln!(stdout)?;
+            Ok(())
+        }
+        Command::Lint { json, programs } => {
+            let mut warnings = Vec::new();
+            for program in &programs {
+                for warning in brainfuck::lint::lint(&std::fs::read(program)?) {
+                    warnings.push((program, warning));
+                }
+            }
+            if json {
+                let warnings: Vec<_> = warnings
+                    .iter()
+                    .map(|(program, warning)| {
+                        let mut value = serde_json::to_value(warning).unwrap();
+                        value["path"] = serde_json::json!(program.display().to_string());
+                        value
+                    })
+                    .collect();
+                serde_json::to_writer_pretty(std::io::stdout(), &warnings)?;
+                println!();
+            } else {
+                for (program, warning) in &warnings {
+                    println!("{}:{}", program.display(), warning);
+                }
+            }
+            if !warnings.is_empty() {
+                return Err(failure::format_err!("{} warnings", warnings.len()));
+            }
+            Ok(())
+        }
+        Command::Minify { program } => {
+            let s = std::fs::read(&program)?;
+            let minified = brainfuck::minify::minify(&s)
+                .map_err(|e| failure::format_err!("{}: {}", program.display(), e))?;
+            eprintln!("{} instructions, from {} bytes", minified.len(), s.len());
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&minified)?;
+            writeln!(stdout)?;
+            Ok(())
+        }
+        Command::Translate { from, to, program } => {
+            let from = from.unwrap_or_else(|| brainfuck::dialect::Dialect::from_path(&program));
+            let (instructions, _) = from.tokenize(&std::fs::read(&program)?, false);
+            std::io::stdout().write_all(&to.emit(&instructions)?)?;
+            Ok(())
+        }
+        Command::Bench {
+            engines,
+            iterations,
+            dir,
+        } => {
+            let engines = if engines.is_empty() {
+                brainfuck::Engine::all()
+            } else {
+                engines
+            };
+            let results = brainfuck::bench::bench_dir(dir, &engines, options, iterations)?;
+            brainfuck::bench::write_table(std::io::stdout(), &results)
+        }
+        Command::DiffEngines { dir } => {
+            let reports = brainfuck::differential::differential_run_dir(dir, options)?;
+            let mut failures = 0;
+            for (path, report) in &reports {
+                match &report.divergence {
+                    Some(divergence) => {
+                        failures += 1;
+                        println!("FAIL {}: {}", path.display(), divergence);
+                    }
+                    None => println!("ok   {}", path.display()),
+                }
+            }
+            if failures > 0 {
+                return Err(failure::format_err!(
+                    "{} of {} programs diverged",
+                    failures,
+                    reports.len()
+                ));
+            }
+            Ok(())
+        }
+        Command::Test {
+            engine,
+            check,
+            bless,
+            timeout,
+            dir,
+        } => {
+            let timeout = std::time::Duration::from_secs(timeout);
+            if bless {
+                for (test, changed) in brainfuck::testcase::bless_dir(dir, timeout, options)? {
+                    let status = if changed { "updated" } else { "ok     " };
+                    println!("{} {}", status, test.path.with_extension("test").display());
+                }
+                return Ok(());
+            }
+            let tests = brainfuck::testcase::load_dir(dir)?;
+            let mut failures = 0;
+            for test in &tests {
+                let test = match test {
+                    Ok(test) => test,
+                    Err(e) => {
+                        failures += 1;
+                        println!("FAIL {}", e);
+                        continue;
+                    }
+                };
+                let verdict = if check {
+                    brainfuck::testcase::check_test(test, timeout, options)
+                } else {
+                    brainfuck::testcase::run_test(test, engine, timeout, options)
+                };
+                match verdict {
+                    brainfuck::testcase::Verdict::Pass => println!("PASS {}", test.path.display()),
+                    brainfuck::testcase::Verdict::Fail(reason) => {
+                        failures += 1;
+                        println!("FAIL {}", test.path.display());
+                        for line in reason.lines() {
+                            println!("    {}", line);
+                        }
+                    }
+                }
+            }
+            println!("{} passed, {} failed", tests.len() - failures, failures);
+            if failures > 0 {
+                return Err(failure::format_err!(
+                    "{} of {} tests failed",
+                    failures,
+                    tests.len()
+                ));
+            }
+            Ok(())
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
     loggerv::init_with_verbosity(opt.verbose).unwrap();
-    let mut f = std::fs::File::open(opt.program)?;
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)?;
+    let mut options = brainfuck::Options {
+        debug_hash: opt.debug_hash,
+        eof: opt.eof,
+        dialect: opt.dialect.clone().unwrap_or_default(),
+        partial_eval: opt.partial_eval,
+        ..brainfuck::Options::default()
+    };
+    if let Some(command) = opt.command {
+        return run_command(command, &options);
+    }
+    let program = opt
+        .program
+        .ok_or_else(|| failure::err_msg("<program> is required"))?;
+    if opt.dialect.is_none() {
+        options.dialect = brainfuck::dialect::Dialect::from_path(&program);
+    }
+    if options.dialect.has_fork() {
+        if opt.profile || opt.trace.is_some() || opt.coverage.is_some() {
+            return Err(failure::err_msg(
+                "--profile, --trace and --coverage do not support brainfork",
+            ));
+        }
+        let scheduling = if opt.os_threads {
+            brainfuck::fork::Scheduling::OsThreads
+        } else {
+            brainfuck::fork::Scheduling::RoundRobin
+        };
+        let buffer = std::fs::read(&program)?;
+        return brainfuck::fork::run_on_tape(
+            &buffer,
+            &options,
+            scheduling,
+            std::io::stdin(),
+            std::io::stdout(),
+            std::io::stderr(),
+            &mut brainfuck::Tape::new(
+                <brainfuck::fork::Brainfork as brainfuck::Brainfuck>::MEMORY_SIZE,
+            ),
+        );
+    }
+    if let Some(lcov) = opt.coverage {
+        let coverage = if Path::new(&program).is_dir() {
+            if opt.bit_language.is_some() {
+                return Err(failure::err_msg(
+                    "--coverage of a directory of test cases does not support --bit-language",
+                ));
+            }
+            brainfuck::coverage::run_suite(&program, &options)?
+        } else {
+            let buffer = std::fs::read(&program)?;
+            let stdin = std::io::stdin();
+            let stdin = stdin.lock();
+            let profile = match opt.bit_language {
+                Some(language) => brainfuck::profile::profile_bits(
+                    language,
+                    &buffer,
+                    stdin,
+                    std::io::stdout(),
+                    std::io::stderr(),
+                    &options,
+                )?,
+                None => brainfuck::profile::profile(
+                    &buffer,
+                    stdin,
+                    std::io::stdout(),
+                    std::io::stderr(),
+                    &options,
+                )?,
+            };
+            let mut coverage = brainfuck::coverage::Coverage::new();
+            coverage.add(&program, &profile)?;
+            coverage
+        };
+        coverage.write_lcov(std::io::BufWriter::new(std::fs::File::create(lcov)?))?;
+        return Ok(());
+    }
+    let buffer = std::fs::read(&program)?;
     let stdin = std::io::stdin();
     let stdin = stdin.lock();
     let stdout = std::io::stdout();
-    brainfuck::run(&buffer, stdin, stdout, opt.optimize, opt.jit)
+    if opt.profile {
+        let profile = match opt.bit_language {
+            Some(language) => brainfuck::profile::profile_bits(
+                language,
+                &buffer,
+                stdin,
+                stdout,
+                std::io::stderr(),
+                &options,
+            )?,
+            None => {
+                brainfuck::profile::profile(&buffer, stdin, stdout, std::io::stderr(), &options)?
+            }
+        };
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        profile.write_report(&mut stderr, opt.profile_top)?;
+        writeln!(stderr)?;
+        profile.write_annotated_source(&mut stderr)?;
+        // The profile of a failing run is still reported, up to the error.
+        return match profile.into_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+    if let Some(trace) = opt.trace {
+        let t = match opt.bit_language {
+            Some(language) => brainfuck::trace::trace_bits(
+                language,
+                &buffer,
+                stdin,
+                stdout,
+                std::io::stderr(),
+                &options,
+            )?,
+            None => brainfuck::trace::trace(&buffer, stdin, stdout, std::io::stderr(), &options)?,
+        };
+        t.write_json(std::io::BufWriter::new(std::fs::File::create(trace)?))?;
+        // The trace of a failing run is still written, up to the error.
+        return match t.into_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+    if let Some(language) = opt.bit_language {
+        let mut tape = brainfuck::Tape::new(language.memory_size());
+        return language.run_on_tape(
+            &buffer,
+            &options,
+            stdin,
+            stdout,
+            std::io::stderr(),
+            &mut tape,
+        );
+    }
+    brainfuck::run_with_options(
+        &buffer,
+        stdin,
+        stdout,
+        std::io::stderr(),
+        opt.optimize,
+        opt.jit,
+        &options,
+    )
 }
@@ -0,0 +1,129 @@
+// Minification of brainfuck programs.
+//
+// Besides dropping everything but the instructions, a program is shrunk until none of these apply:
+//
+// - `+-`, `-+`, `<>` and `><` are removed.
+// - Loops which never run are removed: those right after a `]`, or before any cell is changed,
+//   where the current cell is zero.
+// - `+`, `-`, `<` and `>` at the end of the program are removed.
+//
+// A minified program writes the same output as the original, unless the original moves the
+// pointer off the tape, but it may leave the tape in another state.
+use crate::brainfuck::*;
+use crate::dialect::Dialect;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// `s`, minified.
+pub fn minify(s: &[u8]) -> Result<Vec<u8>> {
+    let (mut instructions, positions) = Dialect::brainfuck().tokenize(s, false);
+    check_brackets(&instructions).map_err(|e| e.at(&positions))?;
+    loop {
+        let minified = remove_trailing(&remove_dead_loops(&cancel_pairs(&instructions)));
+        if minified == instructions {
+            return Ok(minified);
+        }
+        instructions = minified;
+    }
+}
+
+fn cancel_pairs(s: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    for c in s {
+        match (out.last(), c) {
+            (Some(b'+'), b'-') | (Some(b'-'), b'+') | (Some(b'<'), b'>') | (Some(b'>'), b'<') => {
+                out.pop();
+            }
+            _ => out.push(*c),
+        }
+    }
+    out
+}
+
+fn remove_dead_loops(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    // Whether the current cell is known to be zero, and whether every cell is.
+    let mut zero = true;
+    let mut all_zero = true;
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'[' if zero => {
+                i = loop_end(s, i) + 1;
+                continue;
+            }
+            b']' => zero = true,
+            b'<' | b'>' => zero = all_zero,
+            b'.' => {}
+            _ => {
+                zero = false;
+                all_zero = false;
+            }
+        }
+        out.push(s[i]);
+        i += 1;
+    }
+    out
+}
+
+// The offset of the `]` which closes the `[` at `start`.
+fn loop_end(s: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, c) in s.iter().enumerate().skip(start) {
+        match c {
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    unreachable!()
+}
+
+fn remove_trailing(s: &[u8]) -> Vec<u8> {
+    let len = s
+        .iter()
+        .rposition(|c| !b"+-<>".contains(c))
+        .map_or(0, |i| i + 1);
+    s[..len].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn minify_test() {
+        let minified = |s: &[u8]| String::from_utf8(minify(s).unwrap()).unwrap();
+        assert_eq!(minified(b"[comment.]>[-]<+ comment"), "");
+        assert_eq!(minified(b"+><-++[>+<-]>[.][-]+-.>>"), "++[>+<-]>[.].");
+        assert_eq!(minified(b"+[>>+<-<]>[<+].[-],,"), "+[>>+<-<]>[<+].,,");
+        assert_eq!(minified(b"+>[-]<[[+]-+].<<>>"), "+>[-]<[[+]].");
+        assert_eq!(
+            minify(b"+]").err().unwrap().downcast_ref(),
+            Some(&SyntaxError::UnmatchedCloseBracket(1))
+        );
+    }
+
+    #[test]
+    fn testcases_test() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testcases");
+        for test in crate::testcase::load_dir(dir).unwrap() {
+            let test = test.unwrap();
+            let minified = minify(&test.program).unwrap();
+            assert!(minified.len() <= test.program.len());
+            let input = test.expected.feed_in.as_bytes();
+            let mut out = Vec::new();
+            let mut expected = Vec::new();
+            let result = crate::run(&minified, input, &mut out, Some(3), false);
+            let expected_result = crate::run(&test.program, input, &mut expected, Some(3), false);
+            assert_eq!(result.is_ok(), expected_result.is_ok(), "{:?}", test.path);
+            assert_eq!(out, expected, "{:?}", test.path);
+        }
+    }
+}
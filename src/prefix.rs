@@ -0,0 +1,175 @@
+// Partial evaluation of the part of a program before its first input.
+//
+// The instructions are run until the first `,`, a failure, or `MAX_STEPS`, as if on a fresh tape.
+// If that is in a loop, the program is rolled back to the start of the outermost one, so that the
+// rest of the program is a program on its own, which starts from the tape the prefix leaves.
+
+// Instructions run at most, so that compiling a program which runs long or forever ends.
+pub const MAX_STEPS: u64 = 10_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    // What the prefix writes.
+    pub output: Vec<u8>,
+    // The cells the prefix may have changed, from cell 0. The others are zero.
+    pub memory: Vec<u8>,
+    pub data_ptr: usize,
+    // The instructions after the prefix.
+    pub rest: Vec<u8>,
+}
+
+// The state before a loop at the top level of the program.
+struct Snapshot {
+    pc: usize,
+    memory: Vec<u8>,
+    data_ptr: usize,
+    output_len: usize,
+}
+
+// Runs the prefix of `instructions`, which are plain brainfuck with matched brackets, on a tape of
+// `memory_size` cells, for at most `steps` instructions.
+pub fn evaluate(instructions: &[u8], memory_size: usize, mut steps: u64) -> Prefix {
+    let mut jumps = vec![0; instructions.len()];
+    let mut open = Vec::new();
+    for (pc, c) in instructions.iter().enumerate() {
+        match c {
+            b'[' => open.push(pc),
+            b']' => {
+                let start = open.pop().unwrap();
+                jumps[start] = pc;
+                jumps[pc] = start;
+            }
+            _ => {}
+        }
+    }
+
+    let mut memory = vec![0u8; memory_size];
+    // The number of cells from cell 0 the pointer has reached.
+    let mut extent = 1;
+    let mut data_ptr = 0;
+    let mut output = Vec::new();
+    let mut depth = 0;
+    let mut snapshot = None;
+    let mut pc = 0;
+    while pc < instructions.len() && steps > 0 {
+        match instructions[pc] {
+            b'>' if data_ptr + 1 == memory_size => break,
+            b'>' => {
+                data_ptr += 1;
+                extent = std::cmp::max(extent, data_ptr + 1);
+            }
+            b'<' if data_ptr == 0 => break,
+            b'<' => data_ptr -= 1,
+            b'+' => memory[data_ptr] = memory[data_ptr].wrapping_add(1),
+            b'-' => memory[data_ptr] = memory[data_ptr].wrapping_sub(1),
+            b'.' => output.push(memory[data_ptr]),
+            b',' => break,
+            b'[' => {
+                if depth == 0 {
+                    snapshot = Some(Snapshot {
+                        pc,
+                        memory: memory[..extent].to_vec(),
+                        data_ptr,
+                        output_len: output.len(),
+                    });
+                }
+                if memory[data_ptr] == 0 {
+                    pc = jumps[pc];
+                } else {
+                    depth += 1;
+                }
+            }
+            b']' => {
+                if memory[data_ptr] != 0 {
+                    pc = jumps[pc];
+                } else {
+                    depth -= 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+        pc += 1;
+        steps -= 1;
+    }
+
+    if depth > 0 {
+        let snapshot = snapshot.unwrap();
+        output.truncate(snapshot.output_len);
+        return Prefix {
+            output,
+            memory: snapshot.memory,
+            data_ptr: snapshot.data_ptr,
+            rest: instructions[snapshot.pc..].to_vec(),
+        };
+    }
+    memory.truncate(extent);
+    Prefix {
+        output,
+        memory,
+        data_ptr,
+        rest: instructions[pc..].to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::brainfuck::*;
+    use crate::testcase::{self, Verdict};
+
+    fn prefix(s: &[u8], steps: u64) -> (Vec<u8>, Vec<u8>, usize, String) {
+        let prefix = evaluate(s, 4, steps);
+        (
+            prefix.output,
+            prefix.memory,
+            prefix.data_ptr,
+            String::from_utf8(prefix.rest).unwrap(),
+        )
+    }
+
+    #[test]
+    fn evaluate_test() {
+        assert_eq!(
+            prefix(b"++>+++[<+>-]<.,+.", MAX_STEPS),
+            (vec![5], vec![5, 0], 0, ",+.".to_string())
+        );
+        // A loop which reads is run again from its start.
+        assert_eq!(
+            prefix(b"+.[>+.<,]", MAX_STEPS),
+            (vec![1], vec![1], 0, "[>+.<,]".to_string())
+        );
+        assert_eq!(
+            prefix(b"+[]", MAX_STEPS),
+            (vec![], vec![1], 0, "[]".to_string())
+        );
+        assert_eq!(
+            prefix(b">+[>+]", MAX_STEPS),
+            (vec![], vec![0, 1], 1, "[>+]".to_string())
+        );
+        assert_eq!(
+            prefix(b"+++.<", MAX_STEPS),
+            (vec![3], vec![3], 0, "<".to_string())
+        );
+        assert_eq!(prefix(b"+++.", 3), (vec![], vec![3], 0, ".".to_string()));
+    }
+
+    #[test]
+    fn engines_test() {
+        let options = Options {
+            partial_eval: true,
+            ..Options::default()
+        };
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testcases");
+        for test in testcase::load_dir(dir).unwrap() {
+            let test = test.unwrap();
+            for engine in Engine::all() {
+                let timeout = std::time::Duration::from_secs(10);
+                if let Verdict::Fail(reason) = testcase::run_test(&test, engine, timeout, &options)
+                {
+                    panic!("{:?} on {}: {}", test.path, engine, reason);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,480 @@
+// Execution profiler.
+//
+// The program runs on `Interpreter1`, or `BitInterpreter` for a bit language, so every counter
+// belongs to exactly one instruction of the original source.
+use crate::bit::{BitInterpreter, Language};
+use crate::brainfuck::*;
+use std::io::Read;
+use std::io::Write;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// Maximum number of instructions shown for a loop in the report.
+const LOOP_TEXT_WIDTH: usize = 60;
+
+struct Counters {
+    counts: Vec<u64>,
+    loop_entries: Vec<u64>,
+}
+
+impl Observer for Counters {
+    fn instruction(&mut self, pc: usize) {
+        self.counts[pc] += 1;
+    }
+    fn loop_enter(&mut self, loop_start: usize) {
+        self.loop_entries[loop_start] += 1;
+    }
+}
+
+pub struct Profile {
+    source: Vec<u8>,
+    lines: LineIndex,
+    // As `ObservedProgram::text` shows them.
+    instructions: Vec<u8>,
+    // The offset in `source` of each instruction.
+    positions: Vec<usize>,
+    jumptable: Vec<usize>,
+    counts: Vec<u64>,
+    loop_entries: Vec<u64>,
+    // The runtime error which stopped the program, if any. The counts are those up to it.
+    error: Option<failure::Error>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopProfile {
+    // The index of `[` and `]` in the filtered program.
+    pub start: usize,
+    pub end: usize,
+    // 1-based line and column of `[` in the source.
+    pub line: usize,
+    pub column: usize,
+    pub entries: u64,
+    pub iterations: u64,
+}
+
+pub fn profile(
+    s: &[u8],
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+    options: &Options,
+) -> Result<Profile> {
+    let program = ObservedProgram::new(s, options, None)?;
+    let mut tape = Tape::new(program.memory_size());
+    Ok(profile_on_tape(s, &program, read, write, debug, &mut tape))
+}
+
+// Like `profile`, for a program in a bit language.
+pub fn profile_bits(
+    language: Language,
+    s: &[u8],
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+    options: &Options,
+) -> Result<Profile> {
+    let program = ObservedProgram::new(s, options, Some(language))?;
+    let mut tape = Tape::new(program.memory_size());
+    Ok(profile_on_tape(s, &program, read, write, debug, &mut tape))
+}
+
+// Profiles `program`, whose source is `s`.
+pub(crate) fn profile_on_tape(
+    s: &[u8],
+    program: &ObservedProgram,
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+    tape: &mut Tape,
+) -> Profile {
+    let mut counters = Counters {
+        counts: vec![0; program.instructions.len()],
+        loop_entries: vec![0; program.instructions.len()],
+    };
+    let result = program.run_observed(read, write, debug, tape, &mut counters);
+    Profile {
+        source: s.to_vec(),
+        lines: LineIndex::new(s),
+        jumptable: program.create_jumptable(),
+        instructions: program.text.clone(),
+        positions: program.positions.clone(),
+        counts: counters.counts,
+        loop_entries: counters.loop_entries,
+        error: result.err(),
+    }
+}
+
+enum ObservedInterpreter {
+    Bytes(Interpreter1),
+    Bits(BitInterpreter),
+}
+
+// A program to run under an `Observer`: its instructions, as brainfuck, with the offset of each in
+// the source, on `Interpreter1`, or on `BitInterpreter` for a bit language.
+pub(crate) struct ObservedProgram {
+    pub(crate) instructions: Vec<u8>,
+    pub(crate) positions: Vec<usize>,
+    // Each instruction as reports show it: the brainfuck it stands for, or in a bit language, as
+    // it is written, since `*` and `;` are not brainfuck's.
+    pub(crate) text: Vec<u8>,
+    language: Option<Language>,
+    interpreter: ObservedInterpreter,
+}
+
+impl ObservedProgram {
+    // Fails if the brackets of `s` are unbalanced.
+    pub(crate) fn new(
+        s: &[u8],
+        options: &Options,
+        language: Option<Language>,
+    ) -> Result<ObservedProgram> {
+        let (instructions, positions) = match language {
+            None => filter_instructions_with_positions(s, options),
+            Some(language) => language.filter_instructions(s, options),
+        };
+        check_brackets(&instructions).map_err(|e| e.at(&positions))?;
+        let text = match language {
+            None => instructions.clone(),
+            Some(_) => positions.iter().map(|position| s[*position]).collect(),
+        };
+        let interpreter = match language {
+            None => ObservedInterpreter::Bytes(Interpreter1::from_instructions(
+                instructions.clone(),
+                options,
+            )),
+            Some(language) => ObservedInterpreter::Bits(BitInterpreter::from_instructions(
+                language,
+                instructions.clone(),
+                options,
+            )),
+        };
+        Ok(ObservedProgram {
+            instructions,
+            positions,
+            text,
+            language,
+            interpreter,
+        })
+    }
+
+    pub(crate) fn memory_size(&self) -> usize {
+        match self.language {
+            None => Interpreter1::MEMORY_SIZE,
+            Some(language) => language.memory_size(),
+        }
+    }
+
+    fn create_jumptable(&self) -> Vec<usize> {
+        match &self.interpreter {
+            ObservedInterpreter::Bytes(interpreter) => interpreter.create_jumptable(),
+            ObservedInterpreter::Bits(interpreter) => interpreter.create_jumptable(),
+        }
+    }
+
+    pub(crate) fn run_observed(
+        &self,
+        read: impl Read,
+        write: impl Write,
+        debug: impl Write,
+        tape: &mut Tape,
+        observer: &mut impl Observer,
+    ) -> Result<()> {
+        match &self.interpreter {
+            ObservedInterpreter::Bytes(interpreter) => {
+                interpreter.run_observed(read, write, debug, tape, observer)
+            }
+            ObservedInterpreter::Bits(interpreter) => {
+                interpreter.run_observed(read, write, debug, tape, observer)
+            }
+        }
+    }
+}
+
+// The offsets where the lines of a source start, to find the line of an offset without scanning the
+// source each time.
+pub(crate) struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub(crate) fn new(source: &[u8]) -> LineIndex {
+        let mut starts = vec![0];
+        starts.extend(
+            source
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c == b'\n')
+                .map(|(i, _)| i + 1),
+        );
+        LineIndex { starts }
+    }
+
+    // Returns the 1-based line and column of `offset`.
+    pub(crate) fn line_column(&self, offset: usize) -> (usize, usize) {
+        // The number of lines starting at or before `offset`.
+        let line = match self.starts.binary_search(&offset) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        (line, offset - self.starts[line - 1] + 1)
+    }
+}
+
+impl Profile {
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    pub fn positions(&self) -> &[usize] {
+        &self.positions
+    }
+
+    // The number of times each instruction of the filtered program was executed.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn error(&self) -> Option<&failure::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn into_error(self) -> Option<failure::Error> {
+        self.error
+    }
+
+    // All loops, hottest first.
+    pub fn loops(&self) -> Vec<LoopProfile> {
+        let mut loops: Vec<LoopProfile> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c == b'[')
+            .map(|(start, _)| {
+                let end = self.jumptable[start];
+                let (line, column) = self.lines.line_column(self.positions[start]);
+                LoopProfile {
+                    start,
+                    end,
+                    line,
+                    column,
+                    entries: self.loop_entries[start],
+                    // Every iteration ends by executing `]`.
+                    iterations: self.counts[end],
+                }
+            })
+            .collect();
+        loops.sort_by(|a, b| b.iterations.cmp(&a.iterations).then(a.start.cmp(&b.start)));
+        loops
+    }
+
+    fn loop_text(&self, l: &LoopProfile) -> String {
+        let text = &self.instructions[l.start..=l.end];
+        if text.len() > LOOP_TEXT_WIDTH {
+            format!(
+                "{}...",
+                String::from_utf8_lossy(&text[..LOOP_TEXT_WIDTH - 3])
+            )
+        } else {
+            String::from_utf8_lossy(text).into_owned()
+        }
+    }
+
+    pub fn write_report(&self, mut w: impl Write, top: usize) -> Result<()> {
+        let loops = self.loops();
+        writeln!(w, "Executed instructions: {}", self.total())?;
+        writeln!(
+            w,
+            "Hot loops (top {} of {}):",
+            top.min(loops.len()),
+            loops.len()
+        )?;
+        writeln!(
+            w,
+            "{:>4} {:>12} {:>10} {:>10}  source",
+            "rank", "iterations", "entries", "location"
+        )?;
+        for (rank, l) in loops.iter().take(top).enumerate() {
+            writeln!(
+                w,
+                "{:>4} {:>12} {:>10} {:>10}  {}",
+                rank + 1,
+                l.iterations,
+                l.entries,
+                format!("{}:{}", l.line, l.column),
+                self.loop_text(l)
+            )?;
+        }
+        Ok(())
+    }
+
+    // Writes the source with, in the margin, the highest execution count of the instructions on
+    // each line. Lines without instructions have an empty margin.
+    pub fn write_annotated_source(&self, mut w: impl Write) -> Result<()> {
+        let mut line_counts = Vec::new();
+        for (pc, position) in self.positions.iter().enumerate() {
+            let (line, _) = self.lines.line_column(*position);
+            if line_counts.len() < line {
+                line_counts.resize(line, None);
+            }
+            let count = line_counts[line - 1].get_or_insert(0);
+            *count = std::cmp::max(*count, self.counts[pc]);
+        }
+        let mut lines: Vec<&[u8]> = self.source.split(|c| *c == b'\n').collect();
+        if self.source.ends_with(b"\n") {
+            lines.pop();
+        }
+        for (i, line) in lines.into_iter().enumerate() {
+            match line_counts.get(i).cloned().unwrap_or(None) {
+                Some(count) => write!(w, "{:>12} | ", count)?,
+                None => write!(w, "{:>12} | ", "")?,
+            }
+            w.write_all(line)?;
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn profile_test() {
+        let s = b"+++[>++[-]<-]\n>.";
+        let mut out = Vec::new();
+        let profile = profile(
+            s,
+            &[] as &[u8],
+            &mut out,
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            profile.total(),
+            3 + 1 + 3 * (1 + 2 + 1 + 2 * 2 + 1 + 1 + 1) + 2
+        );
+        assert_eq!(
+            profile.loops(),
+            vec![
+                LoopProfile {
+                    start: 7,
+                    end: 9,
+                    line: 1,
+                    column: 8,
+                    entries: 3,
+                    iterations: 6,
+                },
+                LoopProfile {
+                    start: 3,
+                    end: 12,
+                    line: 1,
+                    column: 4,
+                    entries: 1,
+                    iterations: 3,
+                },
+            ]
+        );
+
+        let mut annotated = Vec::new();
+        profile.write_annotated_source(&mut annotated).unwrap();
+        assert_eq!(
+            String::from_utf8(annotated).unwrap(),
+            "           6 | +++[>++[-]<-]\n           1 | >.\n"
+        );
+    }
+
+    #[test]
+    fn profile_bits_test() {
+        let mut out = Vec::new();
+        let profile = profile_bits(
+            Language::Boolfuck,
+            b"+[+;]\n;",
+            &[] as &[u8],
+            &mut out,
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(out, b"\0");
+        assert_eq!(profile.counts(), &[1, 1, 1, 1, 1, 1]);
+        assert_eq!(
+            profile.loops(),
+            vec![LoopProfile {
+                start: 1,
+                end: 4,
+                line: 1,
+                column: 2,
+                entries: 1,
+                iterations: 1,
+            }]
+        );
+        let mut report = Vec::new();
+        profile.write_report(&mut report, 1).unwrap();
+        assert!(String::from_utf8(report).unwrap().ends_with("1:2  [+;]\n"));
+
+        // A Smallfuck program which moves off the tape ends without an error.
+        let profile = profile_bits(
+            Language::Smallfuck,
+            b"*[>*]",
+            &[] as &[u8],
+            std::io::sink(),
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(profile.error().is_none());
+        assert_eq!(
+            profile.counts()[4] as usize,
+            Language::Smallfuck.memory_size() - 1
+        );
+
+        let e = profile_bits(
+            Language::Smallfuck,
+            b"*[",
+            &[] as &[u8],
+            std::io::sink(),
+            std::io::sink(),
+            &Options::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            e.downcast_ref::<SyntaxError>(),
+            Some(&SyntaxError::UnmatchedOpenBracket(1))
+        );
+    }
+
+    #[test]
+    fn profile_unbalanced_test() {
+        let run = |s: &[u8]| {
+            profile(
+                s,
+                &[] as &[u8],
+                std::io::sink(),
+                std::io::sink(),
+                &Options::default(),
+            )
+            .err()
+            .and_then(|e| e.downcast::<SyntaxError>().ok())
+        };
+        assert_eq!(run(b"+["), Some(SyntaxError::UnmatchedOpenBracket(1)));
+        assert_eq!(run(b"]"), Some(SyntaxError::UnmatchedCloseBracket(0)));
+    }
+
+    #[test]
+    fn line_index_test() {
+        let lines = LineIndex::new(b"ab\n\ncd\n");
+        assert_eq!(lines.line_column(0), (1, 1));
+        assert_eq!(lines.line_column(2), (1, 3));
+        assert_eq!(lines.line_column(3), (2, 1));
+        assert_eq!(lines.line_column(5), (3, 2));
+        assert_eq!(lines.line_column(7), (4, 1));
+    }
+}
@@ -0,0 +1,282 @@
+// Scan loops, like `[>]` and `[<<]`, which move the pointer by a stride until it reaches a zero
+// cell.
+//
+// The tape is searched for a zero 16 or 32 cells at a time, with SSE2 or AVX2 when the CPU has it,
+// and 8 cells at a time in a `u64` otherwise. Strides which divide the width of a vector are
+// searched with a mask of the cells the loop visits, and the others one cell at a time.
+use crate::brainfuck::RuntimeError;
+
+// Runs a scan loop of `stride`, like `move_ptr` in a loop: a pointer which would leave the tape
+// stops at its end.
+pub(crate) fn scan_loop(
+    memory: &[u8],
+    data_ptr: &mut usize,
+    stride: isize,
+) -> std::result::Result<(), RuntimeError> {
+    match find_zero(memory, *data_ptr, stride) {
+        Some(zero) => {
+            *data_ptr = zero;
+            Ok(())
+        }
+        None => {
+            *data_ptr = if stride < 0 { 0 } else { memory.len() - 1 };
+            Err(RuntimeError::PointerOutOfBounds)
+        }
+    }
+}
+
+// The first zero cell among `from`, `from + stride`, `from + 2 * stride`, ..., if any is on the
+// tape. `stride` is not zero.
+pub(crate) fn find_zero(memory: &[u8], from: usize, stride: isize) -> Option<usize> {
+    let step = stride.unsigned_abs();
+    if stride > 0 {
+        forward(&memory[from..], step).map(|i| from + i)
+    } else {
+        backward(&memory[..=from], step)
+    }
+}
+
+// The first zero in `s` at a multiple of `step`.
+fn forward(s: &[u8], step: usize) -> Option<usize> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if 32 % step == 0 && is_x86_feature_detected!("avx2") {
+            return unsafe { x86::forward_avx2(s, step) };
+        }
+        if 16 % step == 0 {
+            return unsafe { x86::forward_sse2(s, step) };
+        }
+    }
+    forward_portable(s, step)
+}
+
+// The last zero in `s` at a multiple of `step` from its end.
+fn backward(s: &[u8], step: usize) -> Option<usize> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if 32 % step == 0 && is_x86_feature_detected!("avx2") {
+            return unsafe { x86::backward_avx2(s, step) };
+        }
+        if 16 % step == 0 {
+            return unsafe { x86::backward_sse2(s, step) };
+        }
+    }
+    backward_portable(s, step)
+}
+
+// Whether any byte of `word` is zero.
+fn has_zero(word: u64) -> bool {
+    const ONES: u64 = 0x0101_0101_0101_0101;
+    const HIGHS: u64 = 0x8080_8080_8080_8080;
+    word.wrapping_sub(ONES) & !word & HIGHS != 0
+}
+
+fn word(chunk: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(chunk);
+    u64::from_ne_bytes(bytes)
+}
+
+fn forward_portable(s: &[u8], step: usize) -> Option<usize> {
+    if step != 1 {
+        return forward_scalar(s, step);
+    }
+    let chunks = s.chunks_exact(8);
+    let rest = chunks.remainder();
+    for (i, chunk) in chunks.enumerate() {
+        if has_zero(word(chunk)) {
+            return forward_scalar(chunk, 1).map(|j| i * 8 + j);
+        }
+    }
+    forward_scalar(rest, 1).map(|j| s.len() - rest.len() + j)
+}
+
+fn backward_portable(s: &[u8], step: usize) -> Option<usize> {
+    if step != 1 {
+        return backward_scalar(s, step);
+    }
+    let chunks = s.rchunks_exact(8);
+    let rest = chunks.remainder();
+    for (i, chunk) in chunks.enumerate() {
+        if has_zero(word(chunk)) {
+            return backward_scalar(chunk, 1).map(|j| s.len() - (i + 1) * 8 + j);
+        }
+    }
+    backward_scalar(rest, 1)
+}
+
+fn forward_scalar(s: &[u8], step: usize) -> Option<usize> {
+    s.iter()
+        .step_by(step)
+        .position(|c| *c == 0)
+        .map(|i| i * step)
+}
+
+fn backward_scalar(s: &[u8], step: usize) -> Option<usize> {
+    s.iter()
+        .rev()
+        .step_by(step)
+        .position(|c| *c == 0)
+        .map(|i| s.len() - 1 - i * step)
+}
+
+// The vectors are loaded from offsets which are multiples of their width from the start (or the
+// end) of the slice, so the cells a loop visits are at the same lanes of every vector. The cells
+// left over are searched by `forward_scalar` and `backward_scalar`.
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    // A mask of the lanes at a multiple of `step` from the first lane, or from the last one.
+    fn lanes(width: usize, step: usize, from_last: bool) -> u32 {
+        (0..width)
+            .filter(|lane| {
+                let distance = if from_last { width - 1 - lane } else { *lane };
+                distance % step == 0
+            })
+            .fold(0, |mask, lane| mask | 1 << lane)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn forward_sse2(s: &[u8], step: usize) -> Option<usize> {
+        let lanes = lanes(16, step, false);
+        let zero = _mm_setzero_si128();
+        let mut i = 0;
+        while i + 16 <= s.len() {
+            let chunk = _mm_loadu_si128(s.as_ptr().add(i) as *const __m128i);
+            let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, zero)) as u32 & lanes;
+            if mask != 0 {
+                return Some(i + mask.trailing_zeros() as usize);
+            }
+            i += 16;
+        }
+        super::forward_scalar(&s[i..], step).map(|j| i + j)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn backward_sse2(s: &[u8], step: usize) -> Option<usize> {
+        let lanes = lanes(16, step, true);
+        let zero = _mm_setzero_si128();
+        let mut end = s.len();
+        while end >= 16 {
+            let chunk = _mm_loadu_si128(s.as_ptr().add(end - 16) as *const __m128i);
+            let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, zero)) as u32 & lanes;
+            if mask != 0 {
+                return Some(end - 16 + (31 - mask.leading_zeros()) as usize);
+            }
+            end -= 16;
+        }
+        super::backward_scalar(&s[..end], step)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn forward_avx2(s: &[u8], step: usize) -> Option<usize> {
+        let lanes = lanes(32, step, false);
+        let zero = _mm256_setzero_si256();
+        let mut i = 0;
+        while i + 32 <= s.len() {
+            let chunk = _mm256_loadu_si256(s.as_ptr().add(i) as *const __m256i);
+            let mask = _mm256_movemask_epi8(_mm256_cmpeq_epi8(chunk, zero)) as u32 & lanes;
+            if mask != 0 {
+                return Some(i + mask.trailing_zeros() as usize);
+            }
+            i += 32;
+        }
+        super::forward_scalar(&s[i..], step).map(|j| i + j)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn backward_avx2(s: &[u8], step: usize) -> Option<usize> {
+        let lanes = lanes(32, step, true);
+        let zero = _mm256_setzero_si256();
+        let mut end = s.len();
+        while end >= 32 {
+            let chunk = _mm256_loadu_si256(s.as_ptr().add(end - 32) as *const __m256i);
+            let mask = _mm256_movemask_epi8(_mm256_cmpeq_epi8(chunk, zero)) as u32 & lanes;
+            if mask != 0 {
+                return Some(end - 32 + (31 - mask.leading_zeros()) as usize);
+            }
+            end -= 32;
+        }
+        super::backward_scalar(&s[..end], step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Scans the way the unoptimized loop does.
+    fn naive(memory: &[u8], from: usize, stride: isize) -> Option<usize> {
+        let mut data_ptr = from;
+        while memory[data_ptr] != 0 {
+            data_ptr = data_ptr.wrapping_add(stride as usize);
+            if data_ptr >= memory.len() {
+                return None;
+            }
+        }
+        Some(data_ptr)
+    }
+
+    #[test]
+    fn find_zero_test() {
+        // Tapes with a few zeros, at random.
+        let mut seed = 1u32;
+        let mut random = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            seed >> 16
+        };
+        for &len in &[1, 7, 16, 31, 32, 33, 100, 257] {
+            for _ in 0..20 {
+                let memory: Vec<u8> = (0..len)
+                    .map(|_| if random() % 40 == 0 { 0 } else { 1 })
+                    .collect();
+                for from in 0..len {
+                    for stride in (-17..=17).filter(|stride| *stride != 0) {
+                        let expected = naive(&memory, from, stride);
+                        assert_eq!(find_zero(&memory, from, stride), expected);
+                        let step = stride.unsigned_abs();
+                        let found = if stride > 0 {
+                            forward_portable(&memory[from..], step).map(|i| from + i)
+                        } else {
+                            backward_portable(&memory[..=from], step)
+                        };
+                        assert_eq!(found, expected);
+                        #[cfg(target_arch = "x86_64")]
+                        {
+                            if 16 % step == 0 {
+                                let found = if stride > 0 {
+                                    unsafe { x86::forward_sse2(&memory[from..], step) }
+                                        .map(|i| from + i)
+                                } else {
+                                    unsafe { x86::backward_sse2(&memory[..=from], step) }
+                                };
+                                assert_eq!(found, expected);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scan_loop_test() {
+        let memory = [1, 0, 1, 1, 1];
+        let mut data_ptr = 3;
+        assert_eq!(scan_loop(&memory, &mut data_ptr, -1), Ok(()));
+        assert_eq!(data_ptr, 1);
+        data_ptr = 2;
+        assert_eq!(
+            scan_loop(&memory, &mut data_ptr, 2),
+            Err(RuntimeError::PointerOutOfBounds)
+        );
+        assert_eq!(data_ptr, 4);
+        assert_eq!(
+            scan_loop(&memory, &mut data_ptr, -2),
+            Err(RuntimeError::PointerOutOfBounds)
+        );
+        assert_eq!(data_ptr, 0);
+    }
+}
@@ -0,0 +1,548 @@
+// Test cases in the format of code-for-blog/2017/bfjit/tests/testcases: a brainfuck program
+// `foo.bf` next to a JSON file `foo.test` describing its expected behaviour.
+use crate::brainfuck::*;
+use serde_derive::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expected {
+    #[serde(rename = "feed-in", default)]
+    pub feed_in: String,
+    #[serde(rename = "expect-out", default)]
+    pub expect_out: String,
+    // The kind of error the program is expected to fail with, as given by `error_kind`.
+    #[serde(
+        rename = "expect-error",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expect_error: Option<String>,
+    // The final tape. Cells past the end of the list are expected to be zero.
+    #[serde(
+        rename = "expect-tape",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expect_tape: Option<Vec<u8>>,
+    #[serde(
+        rename = "expect-pointer",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expect_pointer: Option<usize>,
+    #[serde(default, skip_serializing_if = "Config::is_default")]
+    pub config: Config,
+}
+
+// How the machine is set up for a test. Unset fields are the defaults of the engine.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(rename = "tape-size", default, skip_serializing_if = "Option::is_none")]
+    pub tape_size: Option<usize>,
+    // In bits. Every engine has 8-bit cells, so a test asking for any other width fails.
+    #[serde(
+        rename = "cell-width",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cell_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eof: Option<EofPolicy>,
+}
+
+impl Config {
+    pub fn is_default(&self) -> bool {
+        *self == Config::default()
+    }
+}
+
+pub struct TestCase {
+    pub path: PathBuf,
+    pub program: Vec<u8>,
+    pub expected: Expected,
+}
+
+// Every `*.bf` in `dir`, sorted.
+pub fn programs(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let mut paths = glob::glob(&format!("{}/{}", dir.as_ref().display(), "*.bf"))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+// The `feed-in` of the `.test` file of the program at `path`, or nothing if it has none.
+pub fn feed_in(path: &Path) -> Result<Vec<u8>> {
+    if path.with_extension("test").exists() {
+        Ok(load(path.to_path_buf())?.expected.feed_in.into_bytes())
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+// Loads every `*.bf` in `dir` with its `.test` file, sorted by path. A file which does not load
+// is an error of its own, so that the other tests can still run.
+pub fn load_dir(dir: impl AsRef<Path>) -> Result<Vec<Result<TestCase>>> {
+    Ok(programs(dir)?.into_iter().map(load).collect())
+}
+
+// Loads the program at `path` with its `.test` file. Errors name `path`.
+pub fn load(path: PathBuf) -> Result<TestCase> {
+    let load = || -> Result<(Vec<u8>, Expected)> {
+        let program = std::fs::read(&path)?;
+        let test = std::fs::read_to_string(path.with_extension("test"))?;
+        Ok((program, serde_json::from_str(&test)?))
+    };
+    let (program, expected) =
+        load().map_err(|e| failure::format_err!("{}: {}", path.display(), e))?;
+    Ok(TestCase {
+        path,
+        program,
+        expected,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Pass,
+    // Why the test failed, possibly over several lines.
+    Fail(String),
+}
+
+// What the program of a test did.
+struct Execution {
+    output: Vec<u8>,
+    tape: Tape,
+    // As given by `error_kind`, or "panic".
+    error: Option<String>,
+}
+
+// The options and tape size `test` asks for, on a machine whose tape has `memory_size` cells by
+// default. Fails with the reason if the config is not supported.
+pub(crate) fn configure(
+    test: &TestCase,
+    memory_size: usize,
+    options: &Options,
+) -> std::result::Result<(Options, usize), String> {
+    let config = &test.expected.config;
+    match config.cell_width {
+        Some(8) | None => (),
+        Some(width) => {
+            return Err(format!(
+                "unsupported cell-width {}: cells are 8 bits wide",
+                width
+            ))
+        }
+    }
+    if config.tape_size == Some(0) {
+        return Err("tape-size must be at least 1".to_string());
+    }
+    let tape_size = config.tape_size.unwrap_or(memory_size);
+    let mut options = options.clone();
+    if let Some(eof) = config.eof {
+        options.eof = eof;
+    }
+    Ok((options, tape_size))
+}
+
+// Runs the program of `test` on `engine`, set up as the test asks. The program runs on its own
+// thread, which is cancelled if it does not finish within `timeout`. Fails with the reason if the
+// program could not be run to the end.
+fn execute(
+    test: &TestCase,
+    engine: Engine,
+    timeout: Duration,
+    options: &Options,
+) -> std::result::Result<Execution, String> {
+    let (mut options, tape_size) = configure(test, engine.memory_size(), options)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    options.cancel = Some(cancel.clone());
+
+    let (sender, receiver) = mpsc::channel();
+    let program = test.program.clone();
+    let input = test.expected.feed_in.clone().into_bytes();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut tape = Tape::new(tape_size);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            engine.run_on_tape(
+                &program,
+                &options,
+                &input[..],
+                &mut output,
+                std::io::sink(),
+                &mut tape,
+            )
+        }));
+        let error = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(error_kind(&e)),
+            Err(_) => Some("panic".to_string()),
+        };
+        // The receiver is gone if the test has timed out.
+        let _ = sender.send(Execution {
+            output,
+            tape,
+            error,
+        });
+    });
+    receiver.recv_timeout(timeout).map_err(|_| {
+        cancel.store(true, Ordering::Relaxed);
+        format!("timed out after {:?}", timeout)
+    })
+}
+
+// Runs `test` on `engine`.
+pub fn run_test(test: &TestCase, engine: Engine, timeout: Duration, options: &Options) -> Verdict {
+    let Execution {
+        output,
+        tape,
+        error,
+    } = match execute(test, engine, timeout, options) {
+        Ok(execution) => execution,
+        Err(reason) => return Verdict::Fail(reason),
+    };
+
+    let mut reasons = Vec::new();
+    if error != test.expected.expect_error {
+        reasons.push(match (&test.expected.expect_error, &error) {
+            (Some(expected), Some(actual)) => {
+                format!("expected error {}, got {}", expected, actual)
+            }
+            (Some(expected), None) => format!("expected error {}, but succeeded", expected),
+            (None, Some(actual)) => format!("failed with error {}", actual),
+            (None, None) => unreachable!(),
+        });
+    }
+    if output != test.expected.expect_out.as_bytes() {
+        reasons.push(format!(
+            "output differs (-expected +actual):\n{}",
+            diff_lines(&test.expected.expect_out, &String::from_utf8_lossy(&output))
+        ));
+    }
+    if let Some(expected) = &test.expected.expect_tape {
+        let actual = trim_zeros(&tape.memory);
+        let expected = trim_zeros(expected);
+        if let Some(i) = (0..std::cmp::max(actual.len(), expected.len()))
+            .find(|i| actual.get(*i) != expected.get(*i))
+        {
+            reasons.push(format!(
+                "tape differs at cell {}: {}, expected {}",
+                i,
+                actual.get(i).cloned().unwrap_or(0),
+                expected.get(i).cloned().unwrap_or(0)
+            ));
+        }
+    }
+    if let Some(expected) = test.expected.expect_pointer {
+        if tape.data_ptr != expected {
+            reasons.push(format!(
+                "data pointer is {}, expected {}",
+                tape.data_ptr, expected
+            ));
+        }
+    }
+    if reasons.is_empty() {
+        Verdict::Pass
+    } else {
+        Verdict::Fail(reasons.join("\n"))
+    }
+}
+
+// Runs `test` on every engine. The reasons of a failure are prefixed with the failing engines.
+pub fn check_test(test: &TestCase, timeout: Duration, options: &Options) -> Verdict {
+    let mut reasons = Vec::new();
+    for engine in Engine::all() {
+        if let Verdict::Fail(reason) = run_test(test, engine, timeout, options) {
+            reasons.push(format!("{}: {}", engine, reason));
+        }
+    }
+    if reasons.is_empty() {
+        Verdict::Pass
+    } else {
+        Verdict::Fail(reasons.join("\n"))
+    }
+}
+
+// Runs the program at `path` on `Interpreter1`, and records its output and error in its `.test`
+// file. The input and configuration of an existing `.test` file are kept, and its tape and
+// pointer expectations, if any, are updated. The file is only written if its expectations have
+// changed, which is returned along with the updated test.
+pub fn bless(path: PathBuf, timeout: Duration, options: &Options) -> Result<(TestCase, bool)> {
+    let test_path = path.with_extension("test");
+    let previous = if test_path.exists() {
+        Some(serde_json::from_str(&std::fs::read_to_string(&test_path)?)?)
+    } else {
+        None
+    };
+    let expected = previous.clone().unwrap_or_default();
+    let mut test = TestCase {
+        program: std::fs::read(&path)?,
+        path,
+        expected,
+    };
+    let execution = execute(&test, Engine::Interpreter1, timeout, options)
+        .map_err(|reason| failure::format_err!("{}: {}", test.path.display(), reason))?;
+    let output = String::from_utf8(execution.output)
+        .map_err(|_| failure::format_err!("{}: output is not valid UTF-8", test.path.display()))?;
+    let expected = &mut test.expected;
+    expected.expect_out = output;
+    expected.expect_error = execution.error;
+    if expected.expect_tape.is_some() {
+        expected.expect_tape = Some(trim_zeros(&execution.tape.memory).to_vec());
+    }
+    if expected.expect_pointer.is_some() {
+        expected.expect_pointer = Some(execution.tape.data_ptr);
+    }
+    let changed = previous.as_ref() != Some(&test.expected);
+    if changed {
+        std::fs::write(
+            test_path,
+            serde_json::to_string_pretty(&test.expected)? + "\n",
+        )?;
+    }
+    Ok((test, changed))
+}
+
+// Runs `bless` on every `*.bf` in `dir`, sorted by path.
+pub fn bless_dir(
+    dir: impl AsRef<Path>,
+    timeout: Duration,
+    options: &Options,
+) -> Result<Vec<(TestCase, bool)>> {
+    programs(dir)?
+        .into_iter()
+        .map(|path| bless(path, timeout, options))
+        .collect()
+}
+
+fn trim_zeros(cells: &[u8]) -> &[u8] {
+    let len = cells.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+    &cells[..len]
+}
+
+// A line diff of `expected` and `actual`, from their longest common subsequence of lines.
+pub fn diff_lines(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.split('\n').collect();
+    let b: Vec<&str> = actual.split('\n').collect();
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            diff += &format!(" {:?}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff += &format!("-{:?}\n", a[i]);
+            i += 1;
+        } else {
+            diff += &format!("+{:?}\n", b[j]);
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn test_case(program: &[u8], expected: &str) -> TestCase {
+        TestCase {
+            path: PathBuf::from("test.bf"),
+            program: program.to_vec(),
+            expected: serde_json::from_str(expected).unwrap(),
+        }
+    }
+
+    #[test]
+    fn run_test_test() {
+        let timeout = Duration::from_secs(10);
+        let options = Options::default();
+        let test = test_case(b",+.", r#"{"feed-in": "a", "expect-out": "b"}"#);
+        for engine in Engine::all() {
+            assert_eq!(run_test(&test, engine, timeout, &options), Verdict::Pass);
+        }
+
+        let test = test_case(
+            b"+++.,",
+            r#"{"expect-out": "\u0003", "expect-error": "input-unavailable"}"#,
+        );
+        assert_eq!(
+            run_test(&test, Engine::Interpreter3, timeout, &options),
+            Verdict::Pass
+        );
+
+        let test = test_case(b"[", r#"{"expect-out": "x\ny"}"#);
+        assert_eq!(
+            run_test(&test, Engine::Interpreter1, timeout, &options),
+            Verdict::Fail(
+                "failed with error unmatched-open-bracket
+output differs (-expected +actual):
+-\"x\"
+-\"y\"
++\"\"
+"
+                .to_string()
+            )
+        );
+
+        let test = test_case(b"+[]", r#"{}"#);
+        assert_eq!(
+            run_test(
+                &test,
+                Engine::Interpreter3,
+                Duration::from_millis(10),
+                &options
+            ),
+            Verdict::Fail("timed out after 10ms".to_string())
+        );
+    }
+
+    #[test]
+    fn config_test() {
+        let timeout = Duration::from_secs(10);
+        let options = Options::default();
+        let test = test_case(
+            b">+>,>,",
+            r#"{
+                "feed-in": "a",
+                "expect-tape": [0, 1, 97, 255, 0],
+                "expect-pointer": 3,
+                "config": {"eof": "minus-one"}
+            }"#,
+        );
+        for engine in Engine::all() {
+            assert_eq!(run_test(&test, engine, timeout, &options), Verdict::Pass);
+        }
+
+        let test = test_case(
+            b"+>>+>>",
+            r#"{
+                "expect-error": "pointer-out-of-bounds",
+                "expect-tape": [1, 0, 1],
+                "expect-pointer": 3,
+                "config": {"tape-size": 4}
+            }"#,
+        );
+        for engine in Engine::all() {
+            assert_eq!(run_test(&test, engine, timeout, &options), Verdict::Pass);
+        }
+
+        let test = test_case(b"+>", r#"{"expect-tape": [2], "expect-pointer": 0}"#);
+        assert_eq!(
+            run_test(&test, Engine::Interpreter2, timeout, &options),
+            Verdict::Fail(
+                "tape differs at cell 0: 1, expected 2\ndata pointer is 1, expected 0".to_string()
+            )
+        );
+
+        let test = test_case(
+            b"+.",
+            r#"{"expect-out": "\u0001", "config": {"cell-width": 8}}"#,
+        );
+        assert_eq!(
+            run_test(&test, Engine::Interpreter1, timeout, &options),
+            Verdict::Pass
+        );
+        let test = test_case(b"", r#"{"config": {"cell-width": 16}}"#);
+        assert_eq!(
+            run_test(&test, Engine::Interpreter1, timeout, &options),
+            Verdict::Fail("unsupported cell-width 16: cells are 8 bits wide".to_string())
+        );
+
+        // The run is cancelled rather than left running.
+        let test = test_case(b"+[]", "{}");
+        assert_eq!(
+            run_test(&test, Engine::Jit1, Duration::from_millis(50), &options),
+            Verdict::Fail("timed out after 50ms".to_string())
+        );
+    }
+
+    #[test]
+    fn load_dir_test() {
+        let dir = std::env::temp_dir().join(format!("brainfuck-load-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bf"), b"+.").unwrap();
+        std::fs::write(dir.join("a.test"), r#"{"expect-out": "\u0001"}"#).unwrap();
+        std::fs::write(dir.join("b.bf"), b"").unwrap();
+        std::fs::write(dir.join("b.test"), r#"{"config": {"tape-size": "x"}}"#).unwrap();
+
+        // A test which does not load fails on its own, naming its file.
+        let tests = load_dir(&dir).unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].as_ref().unwrap().expected.expect_out, "\u{1}");
+        let error = tests[1].as_ref().err().unwrap().to_string();
+        assert!(
+            error.starts_with(&format!("{}: invalid type", dir.join("b.bf").display())),
+            "{}",
+            error
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bless_test() {
+        let timeout = Duration::from_secs(10);
+        let options = Options::default();
+        let dir = std::env::temp_dir().join(format!("brainfuck-bless-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.bf");
+        std::fs::write(&path, b"+++[>++<-]>.,").unwrap();
+
+        let (test, changed) = bless(path.clone(), timeout, &options).unwrap();
+        assert!(changed);
+        assert_eq!(
+            test.expected,
+            Expected {
+                expect_out: "\u{6}".to_string(),
+                expect_error: Some("input-unavailable".to_string()),
+                ..Expected::default()
+            }
+        );
+        assert_eq!(load(path.clone()).unwrap().expected, test.expected);
+        assert_eq!(check_test(&test, timeout, &options), Verdict::Pass);
+
+        // The input and configuration are kept, and the tape expectation is updated.
+        std::fs::write(
+            path.with_extension("test"),
+            r#"{"feed-in": "", "expect-tape": [1], "config": {"eof": "unchanged"}}"#,
+        )
+        .unwrap();
+        let (test, changed) = bless(path.clone(), timeout, &options).unwrap();
+        assert!(changed);
+        assert_eq!(test.expected.expect_error, None);
+        assert_eq!(test.expected.expect_tape, Some(vec![0, 6]));
+        assert_eq!(test.expected.config.eof, Some(EofPolicy::Unchanged));
+        let (_, changed) = bless(path, timeout, &options).unwrap();
+        assert!(!changed);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn diff_lines_test() {
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nx\nc\nd"),
+            " \"a\"\n-\"b\"\n+\"x\"\n \"c\"\n+\"d\"\n"
+        );
+    }
+}
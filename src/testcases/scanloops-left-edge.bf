@@ -0,0 +1,3 @@
+Set every cell of a tape of 40 cells to 1 and scan off its left end
++>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+
+[<<<]
@@ -0,0 +1,8 @@
+Set c1 to c40 to 1 and scan over them with strides 1 and 2 and 3
+>
++>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>
+<[<]                        Back to c0
+>[>]                        To c41
+<[<<]                       From c40 to c0
+>[>>>]                      From c1 over c40 to c43
++
@@ -0,0 +1,195 @@
+// Brainfuck programs which print a given text.
+//
+// A program prints each byte from whichever of its cells is cheapest to move to and change into
+// it. The shortest programs first set up cells near the bytes of the text with a multiplication
+// loop, such as `++++++++[>+++++++++>++++++++++++<<-]`, where the fastest have no loop, so that
+// every instruction runs once.
+type Result<T> = std::result::Result<T, failure::Error>;
+
+// Number of zero cells a program without a loop may spread the text over.
+const MAX_FREE_CELLS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    // The fewest instructions.
+    Shortest,
+    // The fewest instructions run.
+    Fastest,
+}
+
+impl Strategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::Shortest => "shortest",
+            Strategy::Fastest => "fastest",
+        }
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        [Strategy::Shortest, Strategy::Fastest]
+            .iter()
+            .cloned()
+            .find(|strategy| strategy.name() == s)
+            .ok_or_else(|| failure::format_err!("unknown strategy: {}", s))
+    }
+}
+
+// Number of `+` or `-` which change `from` into `to`.
+fn change_cost(from: u8, to: u8) -> usize {
+    let up = to.wrapping_sub(from) as usize;
+    std::cmp::min(up, 256 - up)
+}
+
+fn emit(program: &mut Vec<u8>, c: u8, n: usize) {
+    program.extend(std::iter::repeat_n(c, n));
+}
+
+// Appends instructions printing `text` to `program`, which leaves the tape as `cells` with the
+// data pointer at `data_ptr`.
+fn print_from(program: &mut Vec<u8>, text: &[u8], cells: &mut [u8], mut data_ptr: usize) {
+    for b in text {
+        let (cell, _) = cells
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, value)| data_ptr.abs_diff(*i) + change_cost(**value, *b))
+            .unwrap();
+        if cell > data_ptr {
+            emit(program, b'>', cell - data_ptr);
+        } else {
+            emit(program, b'<', data_ptr - cell);
+        }
+        data_ptr = cell;
+        let up = b.wrapping_sub(cells[cell]) as usize;
+        if up <= 128 {
+            emit(program, b'+', up);
+        } else {
+            emit(program, b'-', 256 - up);
+        }
+        cells[cell] = *b;
+        program.push(b'.');
+    }
+}
+
+// The program without a loop which prints `text` from up to `cells` zero cells.
+fn without_loop(text: &[u8], cells: usize) -> Vec<u8> {
+    let mut program = Vec::new();
+    print_from(&mut program, text, &mut vec![0; cells], 0);
+    program
+}
+
+// The program which sets cells 1, 2, ... to `factors` times `n` with a loop counting down cell 0,
+// and prints `text` from them.
+fn with_loop(text: &[u8], n: usize, factors: &[usize]) -> Vec<u8> {
+    let mut program = Vec::new();
+    emit(&mut program, b'+', n);
+    program.push(b'[');
+    for factor in factors {
+        program.push(b'>');
+        emit(&mut program, b'+', *factor);
+    }
+    emit(&mut program, b'<', factors.len());
+    program.extend(b"-]");
+    let mut cells = vec![0];
+    cells.extend(factors.iter().map(|factor| (factor * n % 256) as u8));
+    print_from(&mut program, text, &mut cells, 0);
+    program
+}
+
+// The bytes of `text` grouped into ranges at most `width` wide, as the middle of each.
+fn clusters(text: &[u8], width: u8) -> Vec<u8> {
+    let mut bytes = text.to_vec();
+    bytes.sort();
+    bytes.dedup();
+    let mut centers = Vec::new();
+    let mut start = 0;
+    for i in 1..=bytes.len() {
+        if i == bytes.len() || bytes[i] - bytes[start] > width {
+            centers.push(((bytes[start] as usize + bytes[i - 1] as usize) / 2) as u8);
+            start = i;
+        }
+    }
+    centers
+}
+
+// A program printing `text`. Of the programs tried, the one with the fewest instructions, or with
+// the fewest instructions run, is chosen.
+pub fn generate(text: &[u8], strategy: Strategy) -> Vec<u8> {
+    let mut candidates: Vec<Vec<u8>> = (1..=MAX_FREE_CELLS)
+        .map(|cells| without_loop(text, cells))
+        .collect();
+    if strategy == Strategy::Shortest {
+        for width in &[0, 4, 8, 12, 16, 24, 32, 48, 64, 255] {
+            let centers = clusters(text, *width);
+            for n in 2..=16 {
+                let factors: Vec<usize> = centers
+                    .iter()
+                    .map(|center| (*center as usize + n / 2) / n)
+                    .collect();
+                candidates.push(with_loop(text, n, &factors));
+            }
+        }
+    }
+    candidates
+        .into_iter()
+        .min_by_key(|program| program.len())
+        .unwrap()
+}
+
+// The program which prints `text` with `+` alone, on one cell.
+pub fn naive(text: &[u8]) -> Vec<u8> {
+    let mut program = Vec::new();
+    let mut value = 0u8;
+    for b in text {
+        emit(&mut program, b'+', b.wrapping_sub(value) as usize);
+        program.push(b'.');
+        value = *b;
+    }
+    program
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn output(program: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        crate::run_default(program, &[] as &[u8], &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn generate_test() {
+        let all_bytes: Vec<u8> = (0..=255).rev().collect();
+        for text in &[
+            &b""[..],
+            b"Hello, World!\n",
+            b"aAaAaA zzz",
+            b"\xff\x00\x80\x7f",
+            &all_bytes,
+        ] {
+            let shortest = generate(text, Strategy::Shortest);
+            let fastest = generate(text, Strategy::Fastest);
+            assert_eq!(output(&shortest), *text);
+            assert_eq!(output(&fastest), *text);
+            assert_eq!(output(&naive(text)), *text);
+            assert!(shortest.len() <= fastest.len());
+            assert!(fastest.len() <= naive(text).len());
+            assert!(!fastest.contains(&b'['));
+        }
+
+        let hello = b"Hello, World!\n";
+        assert_eq!(naive(hello).len(), 1560);
+        assert!(generate(hello, Strategy::Shortest).len() < 150);
+    }
+}
@@ -0,0 +1,293 @@
+// Execution traces in the Chrome Trace Event format, viewable in chrome://tracing or Perfetto.
+//
+// Each entered loop is a span from its `[` until it exits at its `]`, so spans nest like the
+// brackets. Reads and writes are instant events. Timestamps are the number of instructions executed
+// so far, one at a time as in `Interpreter1`, which keeps traces reproducible.
+use crate::bit::Language;
+use crate::brainfuck::*;
+use crate::profile::{self, ObservedProgram};
+use serde_derive::*;
+use std::io::Read;
+use std::io::Write;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: &'static str,
+    pub ph: &'static str,
+    pub ts: u64,
+    pub pid: u32,
+    pub tid: u32,
+    // The scope of an instant event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<&'static str>,
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub args: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct TraceFile<'a> {
+    #[serde(rename = "traceEvents")]
+    trace_events: &'a [TraceEvent],
+}
+
+pub struct Trace {
+    events: Vec<TraceEvent>,
+    // The runtime error which stopped the program, if any. The events lead up to it.
+    error: Option<failure::Error>,
+}
+
+struct Tracer {
+    // "line:column" of each instruction.
+    locations: Vec<String>,
+    ops: u64,
+    open_loops: Vec<usize>,
+    events: Vec<TraceEvent>,
+}
+
+impl Tracer {
+    fn event(
+        &mut self,
+        name: String,
+        cat: &'static str,
+        ph: &'static str,
+        args: serde_json::Value,
+    ) {
+        self.events.push(TraceEvent {
+            name,
+            cat,
+            ph,
+            ts: self.ops,
+            pid: 1,
+            tid: 1,
+            s: if ph == "i" { Some("t") } else { None },
+            args,
+        });
+    }
+
+    fn io(&mut self, name: &str, pc: usize, byte: u8) {
+        let args = serde_json::json!({ "location": self.locations[pc], "byte": byte });
+        self.event(name.to_string(), "io", "i", args);
+    }
+
+    fn loop_name(&self, loop_start: usize) -> String {
+        format!("loop {}", self.locations[loop_start])
+    }
+}
+
+impl Observer for Tracer {
+    fn instruction(&mut self, _pc: usize) {
+        self.ops += 1;
+    }
+    fn loop_enter(&mut self, loop_start: usize) {
+        self.open_loops.push(loop_start);
+        let name = self.loop_name(loop_start);
+        self.event(name, "loop", "B", serde_json::Value::Null);
+    }
+    fn loop_exit(&mut self, loop_start: usize) {
+        assert_eq!(self.open_loops.pop(), Some(loop_start));
+        let name = self.loop_name(loop_start);
+        self.event(name, "loop", "E", serde_json::Value::Null);
+    }
+    fn input(&mut self, pc: usize, byte: u8) {
+        self.io("read", pc, byte);
+    }
+    fn output(&mut self, pc: usize, byte: u8) {
+        self.io("write", pc, byte);
+    }
+}
+
+pub fn trace(
+    s: &[u8],
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+    options: &Options,
+) -> Result<Trace> {
+    let program = ObservedProgram::new(s, options, None)?;
+    Ok(trace_program(s, &program, read, write, debug))
+}
+
+// Like `trace`, for a program in a bit language.
+pub fn trace_bits(
+    language: Language,
+    s: &[u8],
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+    options: &Options,
+) -> Result<Trace> {
+    let program = ObservedProgram::new(s, options, Some(language))?;
+    Ok(trace_program(s, &program, read, write, debug))
+}
+
+// Traces `program`, whose source is `s`.
+fn trace_program(
+    s: &[u8],
+    program: &ObservedProgram,
+    read: impl Read,
+    write: impl Write,
+    debug: impl Write,
+) -> Trace {
+    let lines = profile::LineIndex::new(s);
+    let mut tracer = Tracer {
+        locations: program
+            .positions
+            .iter()
+            .map(|position| {
+                let (line, column) = lines.line_column(*position);
+                format!("{}:{}", line, column)
+            })
+            .collect(),
+        ops: 0,
+        open_loops: Vec::new(),
+        events: Vec::new(),
+    };
+    let result = program.run_observed(
+        read,
+        write,
+        debug,
+        &mut Tape::new(program.memory_size()),
+        &mut tracer,
+    );
+    // Close the loops left open by an error, so that the trace is still well nested.
+    while let Some(loop_start) = tracer.open_loops.pop() {
+        let name = tracer.loop_name(loop_start);
+        tracer.event(name, "loop", "E", serde_json::Value::Null);
+    }
+    Trace {
+        events: tracer.events,
+        error: result.err(),
+    }
+}
+
+impl Trace {
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    pub fn into_error(self) -> Option<failure::Error> {
+        self.error
+    }
+
+    pub fn write_json(&self, w: impl Write) -> Result<()> {
+        serde_json::to_writer(
+            w,
+            &TraceFile {
+                trace_events: &self.events,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn trace_test() {
+        let s = b"++[>+[-]<-]\n,.";
+        let trace = trace(
+            s,
+            &b"x"[..],
+            std::io::sink(),
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        let events: Vec<(&str, &str, u64)> = trace
+            .events()
+            .iter()
+            .map(|e| (e.name.as_str(), e.ph, e.ts))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("loop 1:3", "B", 3),
+                ("loop 1:6", "B", 6),
+                ("loop 1:6", "E", 8),
+                ("loop 1:6", "B", 14),
+                ("loop 1:6", "E", 16),
+                ("loop 1:3", "E", 19),
+                ("read", "i", 20),
+                ("write", "i", 21),
+            ]
+        );
+
+        let mut json = Vec::new();
+        trace.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json["traceEvents"][6],
+            serde_json::json!({
+                "name": "read", "cat": "io", "ph": "i", "ts": 20, "pid": 1, "tid": 1, "s": "t",
+                "args": { "location": "2:1", "byte": 120 },
+            })
+        );
+    }
+
+    #[test]
+    fn trace_bits_test() {
+        let mut out = Vec::new();
+        let trace = trace_bits(
+            Language::Boolfuck,
+            b",[;,]",
+            &b"\x01"[..],
+            &mut out,
+            std::io::sink(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(out, b"\x01");
+        let events: Vec<(&str, &str, u64)> = trace
+            .events()
+            .iter()
+            .map(|e| (e.name.as_str(), e.ph, e.ts))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("read", "i", 1),
+                ("loop 1:2", "B", 2),
+                ("write", "i", 3),
+                ("read", "i", 4),
+                ("loop 1:2", "E", 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn trace_error_test() {
+        let run = |s: &[u8]| {
+            trace(
+                s,
+                &[] as &[u8],
+                std::io::sink(),
+                std::io::sink(),
+                &Options::default(),
+            )
+        };
+        let e = run(b"+[").err().unwrap();
+        assert_eq!(
+            e.downcast_ref::<SyntaxError>(),
+            Some(&SyntaxError::UnmatchedOpenBracket(1))
+        );
+
+        // The loop left open by the error is closed.
+        let trace = run(b"+[<]").unwrap();
+        let events: Vec<(&str, &str, u64)> = trace
+            .events()
+            .iter()
+            .map(|e| (e.name.as_str(), e.ph, e.ts))
+            .collect();
+        assert_eq!(events, vec![("loop 1:2", "B", 2), ("loop 1:2", "E", 3)]);
+        assert_eq!(
+            trace.into_error().unwrap().downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::PointerOutOfBounds)
+        );
+    }
+}